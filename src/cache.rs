@@ -0,0 +1,133 @@
+use crate::PrList;
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::time::Duration;
+
+pub const DEFAULT_TTL: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Entry<T> {
+    stored_at: DateTime<Utc>,
+    value: T,
+}
+
+/// Short-lived API results reused by quick re-runs.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Cache {
+    /// Authenticated logins, keyed by API host and a hash of the token.
+    #[serde(default)]
+    users: HashMap<String, Entry<String>>,
+    /// `fetch_prs` results, keyed by the search they answer.
+    #[serde(default)]
+    prs: HashMap<String, Entry<PrList>>,
+    #[serde(skip)]
+    ttl: Duration,
+}
+
+impl Cache {
+    fn path() -> Option<PathBuf> {
+        Some(dirs::cache_dir()?.join("deploy").join("cache.json"))
+    }
+
+    /// Loads the cache file. Being only a cache, a missing or unreadable file
+    /// is treated as empty without a warning.
+    pub fn load(ttl: Duration) -> Cache {
+        let cache = Self::path()
+            .and_then(|path| std::fs::read_to_string(path).ok())
+            .and_then(|contents| serde_json::from_str::<Cache>(&contents).ok())
+            .unwrap_or_default();
+        Cache { ttl, ..cache }
+    }
+
+    pub fn save(&mut self) -> Result<()> {
+        let path = Self::path().context("Could not determine the cache directory")?;
+        self.prune();
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)
+                .with_context(|| format!("Failed to create {}", dir.display()))?;
+        }
+        std::fs::write(&path, serde_json::to_string(self)?)
+            .with_context(|| format!("Failed to write {}", path.display()))
+    }
+
+    /// The login `token` authenticates as on `host`, if recently looked up.
+    pub fn user(&self, host: &str, token: &str) -> Option<String> {
+        fresh(&self.users, &user_key(host, token), self.ttl)
+    }
+
+    pub fn store_user(&mut self, host: &str, token: &str, login: &str) {
+        self.users
+            .insert(user_key(host, token), entry(login.to_string()));
+    }
+
+    pub fn prs(&self, key: &str) -> Option<PrList> {
+        fresh(&self.prs, key, self.ttl)
+    }
+
+    pub fn store_prs(&mut self, key: &str, prs: PrList) {
+        self.prs.insert(key.to_string(), entry(prs));
+    }
+
+    /// Drops expired entries so the file does not grow without bound.
+    fn prune(&mut self) {
+        let ttl = self.ttl;
+        self.users.retain(|_, entry| is_fresh(entry, ttl));
+        self.prs.retain(|_, entry| is_fresh(entry, ttl));
+    }
+}
+
+/// Keys a login by `host` and a SHA-256 of `token`, so that switching tokens
+/// looks the login up again without the token itself being written to disk.
+fn user_key(host: &str, token: &str) -> String {
+    let digest = ring::digest::digest(&ring::digest::SHA256, token.as_bytes());
+    let hash: String = digest
+        .as_ref()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect();
+    format!("{}#{}", host, hash)
+}
+
+fn entry<T>(value: T) -> Entry<T> {
+    Entry {
+        stored_at: Utc::now(),
+        value,
+    }
+}
+
+fn is_fresh<T>(entry: &Entry<T>, ttl: Duration) -> bool {
+    let age = Utc::now().signed_duration_since(entry.stored_at);
+    // A negative age, from a clock that moved backwards, fails to_std
+    age.to_std().is_ok_and(|age| age < ttl)
+}
+
+fn fresh<T: Clone>(entries: &HashMap<String, Entry<T>>, key: &str, ttl: Duration) -> Option<T> {
+    entries
+        .get(key)
+        .filter(|entry| is_fresh(entry, ttl))
+        .map(|entry| entry.value.clone())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn logins_are_cached_per_host_and_token() {
+        let mut cache = Cache {
+            ttl: DEFAULT_TTL,
+            ..Cache::default()
+        };
+        cache.store_user("github.com", "token-a", "alice");
+        assert_eq!(
+            cache.user("github.com", "token-a").as_deref(),
+            Some("alice")
+        );
+        assert_eq!(cache.user("github.com", "token-b"), None);
+        assert_eq!(cache.user("github.example.com", "token-a"), None);
+        assert!(cache.users.keys().all(|key| !key.contains("token-a")));
+    }
+}
@@ -0,0 +1,112 @@
+use crate::github;
+use anyhow::Result;
+use octocrab::Octocrab;
+use serde::{Deserialize, Serialize};
+
+/// Check run conclusions that mean the commit is broken.
+const FAILED_CONCLUSIONS: &[&str] = &[
+    "failure",
+    "timed_out",
+    "cancelled",
+    "action_required",
+    "startup_failure",
+];
+
+/// The CI state of a commit, combining commit statuses and check runs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CiState {
+    Passing,
+    Failing,
+    Pending,
+    /// No statuses or check runs were reported, or they could not be read.
+    Unknown,
+}
+
+impl CiState {
+    /// Marker shown next to a PR in the selector.
+    pub fn symbol(self) -> &'static str {
+        match self {
+            CiState::Passing => "✓",
+            CiState::Failing => "✗",
+            CiState::Pending => "•",
+            CiState::Unknown => " ",
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct CombinedStatus {
+    state: String,
+    total_count: u64,
+}
+
+#[derive(Debug, Deserialize)]
+struct CheckRuns {
+    check_runs: Vec<CheckRun>,
+}
+
+#[derive(Debug, Deserialize)]
+struct CheckRun {
+    status: String,
+    conclusion: Option<String>,
+}
+
+/// Fetches the combined status and check runs of `sha`.
+pub async fn ci_state(octocrab: &Octocrab, owner: &str, repo: &str, sha: &str) -> Result<CiState> {
+    let status_route = format!("/repos/{}/{}/commits/{}/status", owner, repo, sha);
+    let checks_route = format!("/repos/{}/{}/commits/{}/check-runs", owner, repo, sha);
+    let checks_params = serde_json::json!({ "per_page": 100 });
+    let (status, checks) = futures::try_join!(
+        github::get::<CombinedStatus, _>(octocrab, &status_route, None::<&()>),
+        github::get::<CheckRuns, _>(octocrab, &checks_route, Some(&checks_params)),
+    )?;
+
+    // The combined state is "pending" when no statuses exist at all
+    let statuses = (status.total_count > 0).then_some(status.state.as_str());
+    let failed = statuses.is_some_and(|state| matches!(state, "failure" | "error"))
+        || checks.check_runs.iter().any(|run| {
+            run.conclusion
+                .as_deref()
+                .is_some_and(|conclusion| FAILED_CONCLUSIONS.contains(&conclusion))
+        });
+    let pending = statuses == Some("pending")
+        || checks
+            .check_runs
+            .iter()
+            .any(|run| run.status != "completed");
+
+    Ok(if failed {
+        CiState::Failing
+    } else if pending {
+        CiState::Pending
+    } else if statuses.is_some() || !checks.check_runs.is_empty() {
+        CiState::Passing
+    } else {
+        CiState::Unknown
+    })
+}
+
+/// A commit status as sent to the statuses API.
+#[derive(Debug, Serialize)]
+pub struct CommitStatus<'a> {
+    /// `pending`, `success`, `failure` or `error`.
+    pub state: &'a str,
+    /// Statuses with the same context replace each other, e.g. `deploy/experimental3`.
+    pub context: String,
+    pub description: String,
+    /// Links the status to the workflow run.
+    pub target_url: Option<&'a str>,
+}
+
+/// Sets `status` on the commit `sha`.
+pub async fn post_status(
+    octocrab: &Octocrab,
+    owner: &str,
+    repo: &str,
+    sha: &str,
+    status: &CommitStatus<'_>,
+) -> Result<()> {
+    let route = format!("/repos/{}/{}/statuses/{}", owner, repo, sha);
+    github::post::<serde_json::Value, _>(octocrab, &route, status).await?;
+    Ok(())
+}
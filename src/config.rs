@@ -0,0 +1,513 @@
+use anyhow::{bail, Context, Result};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::env;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::OnceLock;
+
+/// Name of the repo-local config file, looked up in the working directory.
+const LOCAL_CONFIG_FILE: &str = ".deploy.toml";
+
+/// The settings a config file may supply.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FileConfig {
+    owner: Option<String>,
+    repo: Option<String>,
+    workflow_id: Option<Scalar>,
+    environment_count: Option<u64>,
+    /// Environment names, used instead of generating them from the count.
+    environments: Option<Vec<String>>,
+    base_url: Option<String>,
+    sha_input: Option<String>,
+    target_input: Option<String>,
+    target_template: Option<String>,
+    post_hook: Option<String>,
+    post_hook_fatal: Option<bool>,
+    health_check_url: Option<String>,
+    /// Named sets of environments, offered before the environments themselves.
+    groups: Option<BTreeMap<String, Vec<String>>>,
+    /// Repositories to pick from, each with its own settings.
+    repos: Option<Vec<RepoConfig>>,
+}
+
+/// A repository listed under `[[repos]]`, with the settings that may differ
+/// between repositories.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RepoConfig {
+    owner: String,
+    repo: String,
+    workflow_id: Option<Scalar>,
+    environment_count: Option<u64>,
+    environments: Option<Vec<String>>,
+    sha_input: Option<String>,
+    target_input: Option<String>,
+    target_template: Option<String>,
+    health_check_url: Option<String>,
+    groups: Option<BTreeMap<String, Vec<String>>>,
+}
+
+impl From<RepoConfig> for FileConfig {
+    fn from(repo: RepoConfig) -> FileConfig {
+        FileConfig {
+            owner: Some(repo.owner),
+            repo: Some(repo.repo),
+            workflow_id: repo.workflow_id,
+            environment_count: repo.environment_count,
+            environments: repo.environments,
+            sha_input: repo.sha_input,
+            target_input: repo.target_input,
+            target_template: repo.target_template,
+            health_check_url: repo.health_check_url,
+            groups: repo.groups,
+            ..FileConfig::default()
+        }
+    }
+}
+
+/// A value written either as a string or a bare number, as workflow ids are.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+enum Scalar {
+    Number(u64),
+    Text(String),
+}
+
+impl fmt::Display for Scalar {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Scalar::Number(number) => write!(f, "{}", number),
+            Scalar::Text(text) => f.write_str(text),
+        }
+    }
+}
+
+/// A setting that can come from the environment or a config file.
+#[derive(Debug, Clone, Copy)]
+pub enum Setting {
+    Owner,
+    Repo,
+    WorkflowId,
+    EnvironmentCount,
+    BaseUrl,
+    ShaInput,
+    TargetInput,
+    TargetTemplate,
+    PostHook,
+    PostHookFatal,
+    HealthCheckUrl,
+}
+
+impl Setting {
+    pub const ALL: [Setting; 11] = [
+        Setting::Owner,
+        Setting::Repo,
+        Setting::WorkflowId,
+        Setting::EnvironmentCount,
+        Setting::BaseUrl,
+        Setting::ShaInput,
+        Setting::TargetInput,
+        Setting::TargetTemplate,
+        Setting::PostHook,
+        Setting::PostHookFatal,
+        Setting::HealthCheckUrl,
+    ];
+
+    /// Environment variables that supply the setting, highest priority first.
+    fn env_vars(self) -> &'static [&'static str] {
+        match self {
+            // GITHUB_ORG predates personal accounts being supported
+            Setting::Owner => &["GITHUB_OWNER", "GITHUB_ORG"],
+            Setting::Repo => &["GITHUB_REPO"],
+            Setting::WorkflowId => &["DEPLOY_EXPERIMENTAL_WORKFLOW_ID"],
+            Setting::EnvironmentCount => &["DEPLOY_ENVIRONMENT_COUNT"],
+            Setting::BaseUrl => &["GITHUB_BASE_URL", "GITHUB_API_URL"],
+            Setting::ShaInput => &["DEPLOY_INPUT_SHA_KEY"],
+            Setting::TargetInput => &["DEPLOY_INPUT_TARGET_KEY"],
+            Setting::TargetTemplate => &["DEPLOY_TARGET_TEMPLATE"],
+            Setting::PostHook => &["DEPLOY_POST_HOOK"],
+            Setting::PostHookFatal => &["DEPLOY_POST_HOOK_FATAL"],
+            Setting::HealthCheckUrl => &["DEPLOY_HEALTH_CHECK_URL"],
+        }
+    }
+
+    /// What the setting is for, in error messages.
+    fn purpose(self) -> &'static str {
+        match self {
+            Setting::Owner => "the user or organization the repository belongs to",
+            Setting::Repo => "the name of the repository to deploy",
+            Setting::WorkflowId => "the id or file name of the deploy workflow",
+            Setting::EnvironmentCount => "how many experimental environments to offer",
+            Setting::BaseUrl => "the API root of a GitHub Enterprise Server",
+            Setting::ShaInput => "the name of the workflow input carrying the commit",
+            Setting::TargetInput => "the name of the workflow input carrying the environment",
+            Setting::TargetTemplate => {
+                "the value sent as the environment input, e.g. `{env}/pr-{pr}`"
+            }
+            Setting::PostHook => "a command to run after each dispatch, e.g. `./notify.sh {env}`",
+            Setting::PostHookFatal => "whether a failing post-deploy hook fails the deploy",
+            Setting::HealthCheckUrl => {
+                "the URL --health-check waits on, e.g. `https://{env}.example.com/health`"
+            }
+        }
+    }
+
+    /// Describes how to supply the setting when it is missing, e.g.
+    /// ``GITHUB_REPO (or `repo` in a config file): the name of ...``.
+    pub fn describe_missing(self) -> String {
+        format!(
+            "{} (or `{}` in a config file): {}",
+            self.env_vars().join(" or "),
+            self.key(),
+            self.purpose()
+        )
+    }
+
+    /// The key of the setting in config files.
+    pub fn key(self) -> &'static str {
+        match self {
+            Setting::Owner => "owner",
+            Setting::Repo => "repo",
+            Setting::WorkflowId => "workflow_id",
+            Setting::EnvironmentCount => "environment_count",
+            Setting::BaseUrl => "base_url",
+            Setting::ShaInput => "sha_input",
+            Setting::TargetInput => "target_input",
+            Setting::TargetTemplate => "target_template",
+            Setting::PostHook => "post_hook",
+            Setting::PostHookFatal => "post_hook_fatal",
+            Setting::HealthCheckUrl => "health_check_url",
+        }
+    }
+
+    fn read(self, file: &FileConfig) -> Option<String> {
+        match self {
+            Setting::Owner => file.owner.clone(),
+            Setting::Repo => file.repo.clone(),
+            Setting::WorkflowId => file.workflow_id.as_ref().map(Scalar::to_string),
+            Setting::EnvironmentCount => file.environment_count.map(|count| count.to_string()),
+            Setting::BaseUrl => file.base_url.clone(),
+            Setting::ShaInput => file.sha_input.clone(),
+            Setting::TargetInput => file.target_input.clone(),
+            Setting::TargetTemplate => file.target_template.clone(),
+            Setting::PostHook => file.post_hook.clone(),
+            Setting::PostHookFatal => file.post_hook_fatal.map(|fatal| fatal.to_string()),
+            Setting::HealthCheckUrl => file.health_check_url.clone(),
+        }
+    }
+}
+
+/// A resolved setting along with where it came from, for error messages.
+#[derive(Debug)]
+pub struct Resolved {
+    pub value: String,
+    /// E.g. `GITHUB_OWNER`, or the `owner` key in `~/.config/deploy/config.toml`.
+    pub source: String,
+}
+
+/// The config files found on disk, highest priority first.
+#[derive(Debug, Default)]
+pub struct Config {
+    files: Vec<(PathBuf, FileConfig)>,
+    /// Every path `load` looked at: the `--config-path` file alone, or the
+    /// candidate paths.
+    searched: Vec<PathBuf>,
+    /// The `[[repos]]` entry deployed in this run and where it was found. Its
+    /// settings win over the environment, as the repository was chosen
+    /// explicitly.
+    selected: Option<(String, FileConfig)>,
+    /// Owner, name and URL of the `origin` remote of the working directory's
+    /// clone, read when first needed.
+    origin: OnceLock<Option<(String, String, String)>>,
+}
+
+impl Config {
+    /// The repo-local file in the working directory, then the user's file.
+    fn candidate_paths() -> Vec<PathBuf> {
+        let mut paths = vec![PathBuf::from(LOCAL_CONFIG_FILE)];
+        if let Some(dir) = dirs::config_dir() {
+            paths.push(dir.join("deploy").join("config.toml"));
+        }
+        paths
+    }
+
+    /// Loads every config file that exists, or only `path` when one is given,
+    /// which must then exist. A file that is present but invalid is an error
+    /// rather than being silently ignored.
+    pub fn load(path: Option<&Path>) -> Result<Config> {
+        let (candidates, required) = match path {
+            Some(path) => (vec![path.to_path_buf()], true),
+            None => (Self::candidate_paths(), false),
+        };
+        let mut files = Vec::new();
+        for path in candidates.iter().cloned() {
+            let contents = match std::fs::read_to_string(&path) {
+                Ok(contents) => contents,
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound && !required => continue,
+                Err(err) => {
+                    return Err(err).with_context(|| format!("Failed to read {}", path.display()))
+                }
+            };
+            let file: FileConfig = toml::from_str(&contents)
+                .with_context(|| format!("Invalid config file {}", path.display()))?;
+            files.push((path, file));
+        }
+        Ok(Config {
+            files,
+            searched: candidates,
+            ..Config::default()
+        })
+    }
+
+    /// The config files that were loaded, highest priority first.
+    pub fn paths(&self) -> impl Iterator<Item = &Path> {
+        self.files.iter().map(|(path, _)| path.as_path())
+    }
+
+    /// Resolves `setting` from the environment, then the repo-local file, then
+    /// the user's file. The owner and repository fall back to the `origin`
+    /// remote when neither is set, so they are never mixed from two places.
+    pub fn get(&self, setting: Setting) -> Option<Resolved> {
+        let configured = self.get_configured(setting);
+        if configured.is_some() || !matches!(setting, Setting::Owner | Setting::Repo) {
+            return configured;
+        }
+        let other = match setting {
+            Setting::Owner => Setting::Repo,
+            _ => Setting::Owner,
+        };
+        if self.get_configured(other).is_some() {
+            return None;
+        }
+        let (owner, repo, url) = self.origin.get_or_init(origin_remote).as_ref()?;
+        Some(Resolved {
+            value: match setting {
+                Setting::Owner => owner.clone(),
+                _ => repo.clone(),
+            },
+            source: format!("the origin remote ({})", url),
+        })
+    }
+
+    fn get_configured(&self, setting: Setting) -> Option<Resolved> {
+        if let Some((entry, file)) = &self.selected {
+            if let Some(value) = setting.read(file) {
+                return Some(Resolved {
+                    value,
+                    source: format!("`{}` of {}", setting.key(), entry),
+                });
+            }
+        }
+        let from_env = setting.env_vars().iter().find_map(|var| {
+            env::var(var).ok().map(|value| Resolved {
+                value,
+                source: var.to_string(),
+            })
+        });
+        from_env.or_else(|| {
+            self.files.iter().find_map(|(path, file)| {
+                setting.read(file).map(|value| Resolved {
+                    value,
+                    source: describe_key(setting.key(), path),
+                })
+            })
+        })
+    }
+
+    /// The environment groups of the selected repository or the highest
+    /// priority file that has any, and where they came from. Groups are not
+    /// merged across files.
+    pub fn environment_groups(&self) -> Option<(&BTreeMap<String, Vec<String>>, String)> {
+        if let Some((entry, file)) = &self.selected {
+            if let Some(groups) = &file.groups {
+                return Some((groups, format!("`groups` of {}", entry)));
+            }
+        }
+        self.files.iter().find_map(|(path, file)| {
+            file.groups
+                .as_ref()
+                .map(|groups| (groups, describe_key("groups", path)))
+        })
+    }
+
+    /// The environment names listed for the selected repository, and where
+    /// they came from. Like its other settings, they win over the environment.
+    pub fn repo_environments(&self) -> Option<(&[String], String)> {
+        let (entry, file) = self.selected.as_ref()?;
+        let environments = file.environments.as_deref()?;
+        Some((environments, format!("`environments` of {}", entry)))
+    }
+
+    /// The environment names listed in the highest priority file that has
+    /// them, and where they came from.
+    pub fn environments(&self) -> Option<(&[String], String)> {
+        self.files.iter().find_map(|(path, file)| {
+            file.environments
+                .as_deref()
+                .map(|environments| (environments, describe_key("environments", path)))
+        })
+    }
+
+    /// The `[[repos]]` of the highest priority file that lists any, as owner
+    /// and name. Lists are not merged across files.
+    pub fn repos(&self) -> Vec<(String, String)> {
+        self.repo_entries()
+            .map(|(_, repos)| {
+                repos
+                    .iter()
+                    .map(|entry| (entry.owner.clone(), entry.repo.clone()))
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Makes the `[[repos]]` entry for `owner/repo`, if there is one, supply
+    /// the settings of this run.
+    pub fn select_repo(&mut self, owner: &str, repo: &str) {
+        let Some((path, repos)) = self.repo_entries() else {
+            return;
+        };
+        if let Some(entry) = repos
+            .iter()
+            .find(|entry| entry.owner == owner && entry.repo == repo)
+        {
+            let description = format!("{}/{} in {}", owner, repo, path.display());
+            self.selected = Some((description, entry.clone().into()));
+        }
+    }
+
+    fn repo_entries(&self) -> Option<(&Path, &[RepoConfig])> {
+        self.files
+            .iter()
+            .find_map(|(path, file)| Some((path.as_path(), file.repos.as_deref()?)))
+    }
+
+    /// Like `get`, but fails with every place that was looked at.
+    pub fn require(&self, setting: Setting) -> Result<Resolved> {
+        if let Some(resolved) = self.get(setting) {
+            return Ok(resolved);
+        }
+        let mut tried: Vec<String> = setting
+            .env_vars()
+            .iter()
+            .map(|var| format!("the {} environment variable", var))
+            .collect();
+        tried.extend(
+            self.searched
+                .iter()
+                .map(|path| describe_key(setting.key(), path)),
+        );
+        if matches!(setting, Setting::Owner | Setting::Repo) {
+            tried.push("the origin remote of the git repository in the working directory".into());
+        }
+        let what = match setting {
+            Setting::Owner => "owner (the user or organization the repository belongs to)",
+            _ => setting.key(),
+        };
+        bail!(
+            "No value for {} found. Tried, in order:\n  - {}",
+            what,
+            tried.join("\n  - ")
+        )
+    }
+}
+
+/// The owner and name of the repository the working directory's `origin`
+/// remote points at, along with its URL. Anything that goes wrong, from git
+/// not being installed to not being in a clone, means there is none.
+fn origin_remote() -> Option<(String, String, String)> {
+    let output = Command::new("git")
+        .args(["remote", "get-url", "origin"])
+        .stdin(Stdio::null())
+        .stderr(Stdio::null())
+        .output()
+        .ok()
+        .filter(|output| output.status.success())?;
+    let url = String::from_utf8(output.stdout).ok()?.trim().to_string();
+    let (owner, repo) = parse_remote_url(&url)?;
+    Some((owner, repo, without_credentials(&url)))
+}
+
+/// `url` without a user and password, which HTTPS remotes may embed.
+fn without_credentials(url: &str) -> String {
+    let Some((scheme, rest)) = url.split_once("://") else {
+        return url.to_string();
+    };
+    let authority = rest.split('/').next().unwrap_or(rest);
+    match authority.rsplit_once('@') {
+        Some((_, host)) => format!("{}://{}{}", scheme, host, &rest[authority.len()..]),
+        None => url.to_string(),
+    }
+}
+
+/// Owner and name from an HTTPS or SSH remote URL, e.g.
+/// `https://github.com/octocat/hello-world.git`,
+/// `ssh://git@github.com/octocat/hello-world` or
+/// `git@github.com:octocat/hello-world.git`.
+fn parse_remote_url(url: &str) -> Option<(String, String)> {
+    let path = match url.split_once("://") {
+        Some((_, rest)) => rest.split_once('/')?.1,
+        // scp-like syntax: [user@]host:path
+        None => url.split_once(':')?.1,
+    };
+    let path = path.trim_end_matches('/');
+    let path = path.strip_suffix(".git").unwrap_or(path);
+    let (owner, repo) = path.rsplit_once('/')?;
+    let owner = owner.rsplit('/').next()?;
+    (is_valid_name(owner) && is_valid_name(repo)).then(|| (owner.to_string(), repo.to_string()))
+}
+
+/// Whether `part` can be the owner or the name of a GitHub repository.
+pub fn is_valid_name(part: &str) -> bool {
+    !part.is_empty()
+        && part
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+}
+
+fn describe_key(key: &str, path: &Path) -> String {
+    format!("`{}` in {}", key, path.display())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_remote_url_reads_https_and_ssh_remotes() {
+        let expected = Some(("octocat".to_string(), "hello-world".to_string()));
+        assert_eq!(
+            parse_remote_url("https://github.com/octocat/hello-world.git"),
+            expected
+        );
+        assert_eq!(
+            parse_remote_url("ssh://git@github.com/octocat/hello-world"),
+            expected
+        );
+        assert_eq!(
+            parse_remote_url("git@github.com:octocat/hello-world.git"),
+            expected
+        );
+        assert_eq!(
+            parse_remote_url("git@github.com:octo cat/hello-world.git"),
+            None
+        );
+        assert_eq!(parse_remote_url("/srv/git/hello-world"), None);
+    }
+
+    #[test]
+    fn require_lists_only_the_given_config_path() {
+        let path =
+            std::env::temp_dir().join(format!("deploy-test-{}-config.toml", std::process::id()));
+        std::fs::write(&path, "").unwrap();
+        let config = Config::load(Some(&path)).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let err = config.require(Setting::PostHook).unwrap_err().to_string();
+        assert!(err.contains(&describe_key("post_hook", &path)));
+        assert!(!err.contains(LOCAL_CONFIG_FILE));
+    }
+}
@@ -0,0 +1,54 @@
+use crate::github;
+use anyhow::{Context, Result};
+use octocrab::Octocrab;
+use serde::Deserialize;
+
+#[derive(Debug, Deserialize)]
+struct Created {
+    id: u64,
+}
+
+/// Creates a GitHub deployment of `sha` to `environment`, so the deploy shows
+/// up under the repository's Environments, and returns its id.
+///
+/// The deployment only records the deploy; the workflow does the work, so
+/// GitHub is told not to merge the default branch in or wait for checks.
+pub async fn create(
+    octocrab: &Octocrab,
+    owner: &str,
+    repo: &str,
+    sha: &str,
+    environment: &str,
+    description: &str,
+) -> Result<u64> {
+    let route = format!("/repos/{}/{}/deployments", owner, repo);
+    let body = serde_json::json!({
+        "ref": sha,
+        "environment": environment,
+        "description": description,
+        "auto_merge": false,
+        "required_contexts": [],
+    });
+    let created: Created = github::post(octocrab, &route, &body)
+        .await
+        .with_context(|| format!("Failed to create a deployment to {}", environment))?;
+    Ok(created.id)
+}
+
+/// Adds a status such as `in_progress`, `success` or `failure` to deployment
+/// `id`, linking it to the workflow run at `log_url`.
+pub async fn set_status(
+    octocrab: &Octocrab,
+    owner: &str,
+    repo: &str,
+    id: u64,
+    state: &str,
+    log_url: Option<&str>,
+) -> Result<()> {
+    let route = format!("/repos/{}/{}/deployments/{}/statuses", owner, repo, id);
+    let body = serde_json::json!({ "state": state, "log_url": log_url });
+    github::post::<serde_json::Value, _>(octocrab, &route, &body)
+        .await
+        .with_context(|| format!("Failed to update deployment {}", id))?;
+    Ok(())
+}
@@ -0,0 +1,263 @@
+use crate::config::{Config, Setting};
+use crate::exit::Failure;
+use crate::{github, workflows};
+use anyhow::{bail, Result};
+use octocrab::models::{Author, Repository};
+use octocrab::Octocrab;
+use std::path::Path;
+
+/// Tallies and prints the outcome of each check.
+#[derive(Default)]
+struct Checks {
+    failed: usize,
+}
+
+impl Checks {
+    fn pass(&mut self, what: impl AsRef<str>) {
+        println!("✓ {}", what.as_ref());
+    }
+
+    fn fail(&mut self, what: impl AsRef<str>, hint: impl AsRef<str>) {
+        println!("✗ {}\n    {}", what.as_ref(), hint.as_ref());
+        self.failed += 1;
+    }
+
+    /// Something that could not be verified but is not known to be wrong.
+    fn warn(&mut self, what: impl AsRef<str>, hint: impl AsRef<str>) {
+        println!("! {}\n    {}", what.as_ref(), hint.as_ref());
+    }
+}
+
+/// Checks the configuration, token, repository and workflow, printing a line
+/// for each and failing if any check did.
+pub async fn run(config: &Config, token_file: Option<&Path>) -> Result<()> {
+    let mut checks = Checks::default();
+    crate::configure_requests()?;
+
+    let owner = check_setting(&mut checks, config, Setting::Owner, "The repository owner");
+    let repo = check_setting(&mut checks, config, Setting::Repo, "The repository name");
+
+    let base_url = match crate::resolve_base_url(config) {
+        Ok(base_url) => base_url,
+        Err(err) => {
+            checks.fail(
+                format!("{:#}", err),
+                "Set GITHUB_BASE_URL or `base_url` to your server's API root",
+            );
+            return summarize(&checks);
+        }
+    };
+    let token = match crate::resolve_token(crate::gh_host(base_url.as_ref()), token_file) {
+        Ok(token) => {
+            checks.pass("A GitHub token was found");
+            token
+        }
+        Err(err) => {
+            checks.fail(
+                "No GitHub token was found",
+                format!("{}", err).replace('\n', "\n    "),
+            );
+            return summarize(&checks);
+        }
+    };
+    let octocrab = crate::github_client(Some(token), base_url.as_ref())?;
+
+    let scopes = match github::get_with_headers::<Author>(&octocrab, "/user").await {
+        Ok((user, headers)) => {
+            checks.pass(format!(
+                "The token is valid (authenticated as {})",
+                user.login
+            ));
+            github::oauth_scopes(&headers)
+        }
+        Err(err) if Failure::classify(&err) == Some(Failure::Network) => {
+            checks.fail(
+                format!("Could not reach GitHub: {}", github::one_line(&err)),
+                "Check your network connection and GITHUB_BASE_URL",
+            );
+            return summarize(&checks);
+        }
+        Err(err) => {
+            checks.fail(
+                format!("The token was rejected: {}", github::one_line(&err)),
+                "Generate a new token at https://github.com/settings/tokens or run `gh auth login`",
+            );
+            return summarize(&checks);
+        }
+    };
+
+    let (Some(owner), Some(repo)) = (owner, repo) else {
+        return summarize(&checks);
+    };
+    check_repository(&mut checks, &octocrab, &owner, &repo, scopes.as_deref()).await;
+    check_workflow(&mut checks, config, &octocrab, &owner, &repo).await;
+    summarize(&checks)
+}
+
+fn check_setting(
+    checks: &mut Checks,
+    config: &Config,
+    setting: Setting,
+    name: &str,
+) -> Option<String> {
+    match config.require(setting) {
+        Ok(resolved) => {
+            checks.pass(format!(
+                "{} is {} (from {})",
+                name, resolved.value, resolved.source
+            ));
+            Some(resolved.value)
+        }
+        Err(err) => {
+            checks.fail(
+                format!("{} is not configured", name),
+                format!("{:#}", err).replace('\n', "\n    "),
+            );
+            None
+        }
+    }
+}
+
+async fn check_repository(
+    checks: &mut Checks,
+    octocrab: &Octocrab,
+    owner: &str,
+    repo: &str,
+    scopes: Option<&[String]>,
+) {
+    let route = format!("/repos/{}/{}", owner, repo);
+    let repository: Repository = match github::get(octocrab, &route, None::<&()>).await {
+        Ok(repository) => repository,
+        Err(err) => {
+            checks.fail(
+                format!(
+                    "Repository {}/{} is not accessible: {}",
+                    owner,
+                    repo,
+                    github::one_line(&err)
+                ),
+                "Check GITHUB_OWNER and GITHUB_REPO, and that the token can read the repository",
+            );
+            return;
+        }
+    };
+    checks.pass(format!("Repository {}/{} exists", owner, repo));
+
+    match repository.permissions {
+        Some(permissions) if permissions.push => {
+            checks.pass("The token has write access to the repository")
+        }
+        Some(_) => checks.fail(
+            "The token only has read access to the repository",
+            "Dispatching a workflow needs write access; ask a maintainer for it",
+        ),
+        None => checks.warn(
+            "The token's repository permissions are not reported",
+            "Dispatching a workflow needs write access",
+        ),
+    }
+
+    let private = repository.private.unwrap_or(true);
+    match scopes {
+        // Classic tokens list their scopes; fine-grained tokens do not
+        Some(scopes) => {
+            let sufficient = scopes
+                .iter()
+                .any(|scope| scope == "repo" || (!private && scope == "public_repo"));
+            if sufficient {
+                checks.pass(format!(
+                    "The token's scopes allow dispatch ({})",
+                    scopes.join(", ")
+                ));
+            } else {
+                checks.fail(
+                    format!(
+                        "The token's scopes ({}) do not allow dispatch",
+                        scopes.join(", ")
+                    ),
+                    "Add the `repo` scope to the token",
+                );
+            }
+        }
+        None => checks.warn(
+            "The token's scopes are not reported, as for fine-grained tokens",
+            "Make sure it grants \"Actions: Read and write\" on the repository",
+        ),
+    }
+}
+
+async fn check_workflow(
+    checks: &mut Checks,
+    config: &Config,
+    octocrab: &Octocrab,
+    owner: &str,
+    repo: &str,
+) {
+    let Some(workflow_id) = config.get(Setting::WorkflowId) else {
+        match workflows::dispatchable_workflows(octocrab, owner, repo).await {
+            Ok(found) if !found.is_empty() => checks.pass(format!(
+                "No workflow id is configured; the tool will offer {} dispatchable workflow(s)",
+                found.len()
+            )),
+            Ok(_) => checks.fail(
+                "No workflow id is configured and the repository has no dispatchable workflows",
+                "Add a `workflow_dispatch` trigger to the deploy workflow",
+            ),
+            Err(err) => checks.fail(
+                format!("Could not list workflows: {}", github::one_line(&err)),
+                "Set DEPLOY_EXPERIMENTAL_WORKFLOW_ID to the deploy workflow's id or file name",
+            ),
+        }
+        return;
+    };
+
+    let workflow = match workflows::get_workflow(octocrab, owner, repo, &workflow_id.value).await {
+        Ok(workflow) => workflow,
+        Err(err) => {
+            checks.fail(
+                format!(
+                    "Workflow {} was not found: {}",
+                    workflow_id.value,
+                    github::one_line(&err)
+                ),
+                format!("Check {}", workflow_id.source),
+            );
+            return;
+        }
+    };
+    if workflow.state != "active" {
+        checks.fail(
+            format!("Workflow {} is {}", workflow.path, workflow.state),
+            "Enable it from the repository's Actions tab",
+        );
+        return;
+    }
+    match workflows::fetch_workflow_file(octocrab, owner, repo, &workflow.path, None).await {
+        Ok(file) if file.is_dispatchable() => {
+            checks.pass(format!("Workflow {} can be dispatched", workflow.path))
+        }
+        Ok(_) => checks.fail(
+            format!(
+                "Workflow {} has no workflow_dispatch trigger",
+                workflow.path
+            ),
+            "Add `workflow_dispatch:` to its `on:` section",
+        ),
+        Err(err) => checks.warn(
+            format!(
+                "Could not read {}: {}",
+                workflow.path,
+                github::one_line(&err)
+            ),
+            "Its workflow_dispatch trigger could not be verified",
+        ),
+    }
+}
+
+fn summarize(checks: &Checks) -> Result<()> {
+    if checks.failed > 0 {
+        bail!("{} check(s) failed", checks.failed);
+    }
+    println!("All checks passed");
+    Ok(())
+}
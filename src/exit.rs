@@ -0,0 +1,123 @@
+use crate::github::{ApiError, Timeout};
+use crate::state;
+use dialoguer::console::Term;
+use http::{Method, StatusCode};
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+/// Appended to `--help`.
+pub const EXIT_CODES_HELP: &str = "\
+Exit codes:
+  0    Success
+  1    Any other failure, including a workflow run that failed
+  2    Authentication or permission problem
+  3    No matching pull request
+  4    Workflow dispatch rejected, e.g. inputs that do not match the workflow
+  5    Network failure, timeout, rate limit or GitHub outage
+  6    --wait gave up before the workflow run finished
+  130  Interrupted with Ctrl-C";
+
+/// Failure classes with stable exit codes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Failure {
+    Auth,
+    NoPr,
+    Rejected,
+    Network,
+    WaitTimeout,
+}
+
+impl Failure {
+    pub fn code(self) -> u8 {
+        match self {
+            Failure::Auth => 2,
+            Failure::NoPr => 3,
+            Failure::Rejected => 4,
+            Failure::Network => 5,
+            Failure::WaitTimeout => 6,
+        }
+    }
+
+    /// Finds the class of `err` from the first cause that has one.
+    pub fn classify(err: &anyhow::Error) -> Option<Failure> {
+        err.chain().find_map(|cause| {
+            if let Some(failure) = cause.downcast_ref::<Failure>() {
+                return Some(*failure);
+            }
+            if cause.is::<Timeout>() {
+                return Some(Failure::Network);
+            }
+            if let Some(api_err) = cause.downcast_ref::<ApiError>() {
+                return Self::classify_api_error(api_err);
+            }
+            match cause.downcast_ref::<octocrab::Error>() {
+                Some(octocrab::Error::Hyper { .. } | octocrab::Error::Service { .. }) => {
+                    Some(Failure::Network)
+                }
+                _ => None,
+            }
+        })
+    }
+
+    fn classify_api_error(err: &ApiError) -> Option<Failure> {
+        if err.exhausted_rate_limit().is_some() || err.status.is_server_error() {
+            return Some(Failure::Network);
+        }
+        match err.status {
+            StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => Some(Failure::Auth),
+            StatusCode::TOO_MANY_REQUESTS => Some(Failure::Network),
+            status
+                if status.is_client_error()
+                    && err.method == Method::POST
+                    && err.route.ends_with("/dispatches") =>
+            {
+                Some(Failure::Rejected)
+            }
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Failure::Auth => "authentication or permission problem",
+            Failure::NoPr => "no matching pull request",
+            Failure::Rejected => "workflow dispatch rejected",
+            Failure::Network => "network problem",
+            Failure::WaitTimeout => "gave up waiting for the workflow run",
+        })
+    }
+}
+
+impl std::error::Error for Failure {}
+
+/// Whether `err` comes from a prompt that Ctrl-C interrupted.
+pub fn is_interrupted(err: &anyhow::Error) -> bool {
+    err.chain().any(|cause| {
+        cause
+            .downcast_ref::<std::io::Error>()
+            .is_some_and(|err| err.kind() == std::io::ErrorKind::Interrupted)
+    })
+}
+
+/// Ends the run after Ctrl-C: restores the cursor a prompt may have hidden,
+/// removes the state file lock if it is held and exits with 130. A prompt
+/// reports the same Ctrl-C as an error, so only the first call prints.
+pub fn interrupted() -> ! {
+    // Every caller releases the lock, which waits out a removal already under
+    // way, so neither exits while the other is still removing it
+    state::release_lock();
+    if !INTERRUPTED.swap(true, Ordering::SeqCst) {
+        let _ = Term::stderr().show_cursor();
+        eprintln!("\nCancelled");
+    }
+    std::process::exit(130)
+}
+
+/// An error reading `message`, classified as `failure`.
+pub fn fail(failure: Failure, message: impl fmt::Display + Send + Sync + 'static) -> anyhow::Error {
+    anyhow::Error::new(failure).context(message)
+}
@@ -0,0 +1,483 @@
+use anyhow::{Context, Result};
+use chrono::{DateTime, Local, TimeZone, Utc};
+use http::{HeaderMap, Method, StatusCode};
+use octocrab::Octocrab;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+pub const DEFAULT_MAX_RETRIES: u32 = 3;
+pub const DEFAULT_HTTP_TIMEOUT: Duration = Duration::from_secs(30);
+const BASE_RETRY_DELAY: Duration = Duration::from_secs(1);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(30);
+
+/// Remaining requests below which a warning is printed.
+const LOW_RATE_LIMIT_THRESHOLD: u64 = 10;
+
+static MAX_RETRIES: AtomicU32 = AtomicU32::new(DEFAULT_MAX_RETRIES);
+static HTTP_TIMEOUT_MS: AtomicU64 = AtomicU64::new(DEFAULT_HTTP_TIMEOUT.as_millis() as u64);
+static WARNED_RATE_LIMIT: AtomicBool = AtomicBool::new(false);
+
+/// Sets how many times a transient failure is retried before giving up.
+pub fn set_max_retries(retries: u32) {
+    MAX_RETRIES.store(retries, Ordering::Relaxed);
+}
+
+/// Sets how long a single request may take, response body included.
+pub fn set_http_timeout(timeout: Duration) {
+    HTTP_TIMEOUT_MS.store(timeout.as_millis() as u64, Ordering::Relaxed);
+}
+
+pub fn max_retries() -> u32 {
+    MAX_RETRIES.load(Ordering::Relaxed)
+}
+
+pub fn http_timeout() -> Duration {
+    Duration::from_millis(HTTP_TIMEOUT_MS.load(Ordering::Relaxed))
+}
+
+/// A request that got no complete response within the HTTP timeout.
+#[derive(Debug)]
+pub struct Timeout {
+    pub after: Duration,
+}
+
+impl fmt::Display for Timeout {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "no response from GitHub within {}s (the network may be down; DEPLOY_HTTP_TIMEOUT raises the limit)",
+            self.after.as_secs()
+        )
+    }
+}
+
+impl std::error::Error for Timeout {}
+
+/// Bounds a request by the HTTP timeout.
+async fn timed<T, E>(request: impl Future<Output = std::result::Result<T, E>>) -> Result<T>
+where
+    E: std::error::Error + Send + Sync + 'static,
+{
+    let after = http_timeout();
+    match tokio::time::timeout(after, request).await {
+        Ok(result) => Ok(result?),
+        Err(_) => Err(Timeout { after }.into()),
+    }
+}
+
+/// The rate limit state reported by the `X-RateLimit-*` response headers.
+#[derive(Debug, Clone, Copy)]
+pub struct RateLimit {
+    pub remaining: u64,
+    pub reset: DateTime<Utc>,
+}
+
+impl RateLimit {
+    fn from_headers(headers: &HeaderMap) -> Option<RateLimit> {
+        let header = |name: &str| -> Option<i64> { headers.get(name)?.to_str().ok()?.parse().ok() };
+        Some(RateLimit {
+            remaining: header("x-ratelimit-remaining")?.try_into().ok()?,
+            reset: Utc
+                .timestamp_opt(header("x-ratelimit-reset")?, 0)
+                .single()?,
+        })
+    }
+
+    /// Describes when the limit resets, in local time.
+    pub fn describe_reset(&self) -> String {
+        let minutes = (self.reset - Utc::now()).num_minutes().max(0);
+        format!(
+            "{} (in {} min)",
+            self.reset.with_timezone(&Local).format("%H:%M:%S"),
+            minutes
+        )
+    }
+}
+
+/// A non-success response from the GitHub API, keeping the status code and
+/// headers that octocrab's own errors drop.
+#[derive(Debug)]
+pub struct ApiError {
+    pub method: Method,
+    pub route: String,
+    pub status: StatusCode,
+    pub message: String,
+    pub headers: HeaderMap,
+}
+
+impl ApiError {
+    /// The delay requested by GitHub through `Retry-After`, if any.
+    pub fn retry_after(&self) -> Option<Duration> {
+        self.headers
+            .get(http::header::RETRY_AFTER)?
+            .to_str()
+            .ok()?
+            .trim()
+            .parse()
+            .ok()
+            .map(Duration::from_secs)
+    }
+
+    /// The primary rate limit, when this failure was caused by exhausting it.
+    pub fn exhausted_rate_limit(&self) -> Option<RateLimit> {
+        let limit = RateLimit::from_headers(&self.headers)?;
+        let limited = matches!(
+            self.status,
+            StatusCode::FORBIDDEN | StatusCode::TOO_MANY_REQUESTS
+        );
+        (limited && limit.remaining == 0).then_some(limit)
+    }
+
+    /// Explains a rejected token: a 401 means it is invalid or expired, and a
+    /// 403 listing accepted scopes the token lacks names them.
+    pub fn auth_explanation(&self) -> Option<String> {
+        match self.status {
+            StatusCode::UNAUTHORIZED => Some(
+                "Your GitHub token is invalid or expired. Generate a new one at https://github.com/settings/tokens or run `gh auth login`"
+                    .to_string(),
+            ),
+            StatusCode::FORBIDDEN => {
+                let scopes = |name| scope_list(&self.headers, name).unwrap_or_default();
+                // Any one of the accepted scopes is enough
+                let accepted = scopes("x-accepted-oauth-scopes");
+                let granted = scopes("x-oauth-scopes");
+                if accepted.is_empty() || accepted.iter().any(|scope| granted.contains(scope)) {
+                    return None;
+                }
+                Some(format!(
+                    "Your GitHub token lacks the `{}` scope needed for {} {}; it has: {}",
+                    accepted.join("` or `"),
+                    self.method,
+                    self.route,
+                    if granted.is_empty() {
+                        "no scopes".to_string()
+                    } else {
+                        granted.join(", ")
+                    }
+                ))
+            }
+            _ => None,
+        }
+    }
+
+    /// Whether retrying the request could plausibly succeed. Requests that are
+    /// not idempotent are only retried when GitHub clearly did not handle them.
+    fn is_transient(&self, idempotent: bool) -> bool {
+        // Waiting out an exhausted primary limit can take up to an hour
+        if self.exhausted_rate_limit().is_some() && self.retry_after().is_none() {
+            return false;
+        }
+        match self.status {
+            StatusCode::SERVICE_UNAVAILABLE | StatusCode::TOO_MANY_REQUESTS => true,
+            // A gateway may give up on a request the server still completes
+            StatusCode::INTERNAL_SERVER_ERROR
+            | StatusCode::BAD_GATEWAY
+            | StatusCode::GATEWAY_TIMEOUT => idempotent,
+            // Secondary rate limits come back as 403 with a Retry-After
+            StatusCode::FORBIDDEN => self.retry_after().is_some(),
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(limit) = self.exhausted_rate_limit() {
+            return write!(
+                f,
+                "GitHub API rate limit exhausted ({} {}); it resets at {}",
+                self.method,
+                self.route,
+                limit.describe_reset()
+            );
+        }
+        write!(
+            f,
+            "{} {} returned {}: {}",
+            self.method, self.route, self.status, self.message
+        )
+    }
+}
+
+impl std::error::Error for ApiError {}
+
+/// Wraps `err` with `context`, unless it is an exhausted rate limit or a
+/// timeout, whose own message already says what went wrong and that the
+/// request itself was not at fault. A rejected token gets an explanation of
+/// what is wrong with it instead of `context`.
+pub fn context_unless_explained(err: anyhow::Error, context: String) -> anyhow::Error {
+    let api_err = err.downcast_ref::<ApiError>();
+    let rate_limited = api_err.is_some_and(|api_err| api_err.exhausted_rate_limit().is_some());
+    if rate_limited || err.is::<Timeout>() {
+        return err;
+    }
+    match api_err.and_then(ApiError::auth_explanation) {
+        Some(explanation) => err.context(explanation),
+        None => err.context(context),
+    }
+}
+
+/// The error chain on one line, leaving out the backtrace that octocrab's
+/// errors embed in their message.
+pub fn one_line(err: &anyhow::Error) -> String {
+    let message = format!("{:#}", err);
+    message.lines().next().unwrap_or_default().to_string()
+}
+
+#[derive(Deserialize)]
+struct ErrorBody {
+    message: String,
+}
+
+/// The scopes in a comma-separated scope header, if it is present.
+fn scope_list(headers: &HeaderMap, name: &str) -> Option<Vec<String>> {
+    let value = headers.get(name)?.to_str().ok()?;
+    Some(
+        value
+            .split(',')
+            .map(|scope| scope.trim().to_string())
+            .filter(|scope| !scope.is_empty())
+            .collect(),
+    )
+}
+
+/// The scopes a classic token was granted, from the `X-OAuth-Scopes` header
+/// of any response to it. `None` for fine-grained tokens, which do not report
+/// them.
+pub fn oauth_scopes(headers: &HeaderMap) -> Option<Vec<String>> {
+    scope_list(headers, "x-oauth-scopes")
+}
+
+/// Percent-encodes a branch or tag name for use in a route, e.g.
+/// `feature/fix#1` as `feature/fix%231`. Slashes are kept, as GitHub matches
+/// them against the ref's path.
+pub fn encode_ref(git_ref: &str) -> String {
+    let mut encoded = String::with_capacity(git_ref.len());
+    for byte in git_ref.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' | b'/' => {
+                encoded.push(byte as char)
+            }
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    encoded
+}
+
+/// Sends a GET request to `route`, retrying transient failures.
+pub async fn get<T, P>(octocrab: &Octocrab, route: &str, params: Option<&P>) -> Result<T>
+where
+    T: DeserializeOwned,
+    P: Serialize + ?Sized,
+{
+    let route = match params {
+        Some(params) => format!("{}?{}", route, serde_urlencoded::to_string(params)?),
+        None => route.to_string(),
+    };
+    retry(true, || send(octocrab, Method::GET, &route, None::<&()>)).await
+}
+
+/// Like `get`, also returning the response headers, e.g. to read the token's
+/// `X-OAuth-Scopes`.
+pub async fn get_with_headers<T>(octocrab: &Octocrab, route: &str) -> Result<(T, HeaderMap)>
+where
+    T: DeserializeOwned,
+{
+    retry(true, || {
+        send_with_headers(octocrab, Method::GET, route, None::<&()>)
+    })
+    .await
+}
+
+/// Sends a POST request to `route`. Only failures where GitHub clearly did not
+/// act on the request are retried.
+pub async fn post<T, B>(octocrab: &Octocrab, route: &str, body: &B) -> Result<T>
+where
+    T: DeserializeOwned,
+    B: Serialize + ?Sized,
+{
+    retry(false, || send(octocrab, Method::POST, route, Some(body))).await
+}
+
+/// Sends a PATCH request to `route`, retrying transient failures since
+/// sending the same fields again changes nothing.
+pub async fn patch<T, B>(octocrab: &Octocrab, route: &str, body: &B) -> Result<T>
+where
+    T: DeserializeOwned,
+    B: Serialize + ?Sized,
+{
+    retry(true, || send(octocrab, Method::PATCH, route, Some(body))).await
+}
+
+async fn send<T, B>(octocrab: &Octocrab, method: Method, route: &str, body: Option<&B>) -> Result<T>
+where
+    T: DeserializeOwned,
+    B: Serialize + ?Sized,
+{
+    Ok(send_with_headers(octocrab, method, route, body).await?.0)
+}
+
+async fn retry<T, F, Fut>(idempotent: bool, mut request: F) -> Result<T>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T>>,
+{
+    let max_retries = max_retries();
+    let mut attempt = 0;
+    loop {
+        let err = match request().await {
+            Ok(value) => return Ok(value),
+            Err(err) => err,
+        };
+
+        let delay = match err.downcast_ref::<ApiError>() {
+            Some(api_err) if api_err.is_transient(idempotent) => api_err
+                .retry_after()
+                .unwrap_or_else(|| backoff(attempt))
+                .min(MAX_RETRY_DELAY),
+            Some(_) => return Err(err),
+            // No response at all, e.g. a dropped or hung connection
+            None if idempotent && (is_connection_error(&err) || err.is::<Timeout>()) => {
+                backoff(attempt)
+            }
+            None => return Err(err),
+        };
+        if attempt >= max_retries {
+            return Err(err);
+        }
+
+        attempt += 1;
+        eprintln!(
+            "{}; retrying in {}s (attempt {}/{})",
+            one_line(&err),
+            delay.as_secs(),
+            attempt,
+            max_retries
+        );
+        tokio::time::sleep(delay).await;
+    }
+}
+
+/// Whether `err` leaves open if GitHub acted on the request: no response
+/// arrived, or a server error that may have come after the work was done.
+pub fn is_unanswered(err: &anyhow::Error) -> bool {
+    if let Some(api_err) = err.downcast_ref::<ApiError>() {
+        return matches!(
+            api_err.status,
+            StatusCode::INTERNAL_SERVER_ERROR
+                | StatusCode::BAD_GATEWAY
+                | StatusCode::GATEWAY_TIMEOUT
+        );
+    }
+    is_connection_error(err) || err.is::<Timeout>()
+}
+
+/// Whether `err` is GitHub answering 404, which it also does for resources
+/// the token may not see.
+pub fn is_not_found(err: &anyhow::Error) -> bool {
+    err.downcast_ref::<ApiError>()
+        .is_some_and(|api_err| api_err.status == StatusCode::NOT_FOUND)
+}
+
+fn is_connection_error(err: &anyhow::Error) -> bool {
+    matches!(
+        err.downcast_ref::<octocrab::Error>(),
+        Some(octocrab::Error::Hyper { .. } | octocrab::Error::Service { .. })
+    )
+}
+
+fn backoff(attempt: u32) -> Duration {
+    BASE_RETRY_DELAY
+        .saturating_mul(2u32.saturating_pow(attempt))
+        .min(MAX_RETRY_DELAY)
+}
+
+async fn send_with_headers<T, B>(
+    octocrab: &Octocrab,
+    method: Method,
+    route: &str,
+    body: Option<&B>,
+) -> Result<(T, HeaderMap)>
+where
+    T: DeserializeOwned,
+    B: Serialize + ?Sized,
+{
+    // Only the method and route are logged; the token lives in a header
+    tracing::debug!("{} {}", method, route);
+    let started = Instant::now();
+    let exchange = async {
+        let response = match method {
+            Method::POST => octocrab._post(route, body).await?,
+            Method::PATCH => octocrab._patch(route, body).await?,
+            _ => octocrab._get(route).await?,
+        };
+        let status = response.status();
+        let headers = response.headers().clone();
+        let text = octocrab.body_to_string(response).await?;
+        Ok::<_, octocrab::Error>((status, headers, text))
+    };
+    let (status, headers, text) = timed(exchange)
+        .await
+        .with_context(|| format!("{} {}", method, route.split('?').next().unwrap_or(route)))?;
+    tracing::debug!(
+        "{} {} -> {} in {}ms",
+        method,
+        route,
+        status,
+        started.elapsed().as_millis()
+    );
+
+    if let Some(limit) = RateLimit::from_headers(&headers) {
+        if status.is_success()
+            && limit.remaining < LOW_RATE_LIMIT_THRESHOLD
+            && !WARNED_RATE_LIMIT.swap(true, Ordering::Relaxed)
+        {
+            eprintln!(
+                "Warning: only {} GitHub API requests left; the limit resets at {}",
+                limit.remaining,
+                limit.describe_reset()
+            );
+        }
+    }
+
+    if !status.is_success() {
+        let message = serde_json::from_str::<ErrorBody>(&text)
+            .map(|body| body.message)
+            .unwrap_or_else(|_| text.trim().chars().take(200).collect());
+        return Err(ApiError {
+            method,
+            route: route.split('?').next().unwrap_or(route).to_string(),
+            status,
+            message,
+            headers,
+        }
+        .into());
+    }
+
+    // Endpoints such as workflow dispatch answer with an empty 204
+    let text = if text.trim().is_empty() {
+        "null"
+    } else {
+        &text
+    };
+    let value = serde_json::from_str(text)
+        .with_context(|| format!("Unexpected response from {}", route))?;
+    Ok((value, headers))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encode_ref_escapes_all_but_unreserved_characters_and_slashes() {
+        assert_eq!(encode_ref("feat/a#b"), "feat/a%23b");
+        assert_eq!(encode_ref("release/1.2_rc-3~x"), "release/1.2_rc-3~x");
+        assert_eq!(encode_ref("fix?q=1&x%"), "fix%3Fq%3D1%26x%25");
+        assert_eq!(encode_ref("ü"), "%C3%BC");
+    }
+}
@@ -0,0 +1,59 @@
+use crate::github;
+use crate::template;
+use anyhow::{bail, Context, Result};
+use std::time::{Duration, Instant};
+use url::Url;
+
+/// How often an environment that is not healthy yet is checked again.
+const CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Where to check that an environment is up, e.g.
+/// `https://{env}.example.com/health`.
+#[derive(Debug)]
+pub struct HealthCheck {
+    template: String,
+}
+
+impl HealthCheck {
+    /// Checks that `template` only uses `{env}` and renders to a URL.
+    pub fn parse(template: &str) -> Result<HealthCheck> {
+        template::check(template, &["env"], "health-check URL")?;
+        let check = HealthCheck {
+            template: template.to_string(),
+        };
+        Url::parse(&check.url("env"))
+            .with_context(|| format!("'{}' is not a valid health-check URL", template))?;
+        Ok(check)
+    }
+
+    /// The URL to check for `environment`.
+    pub fn url(&self, environment: &str) -> String {
+        template::render(&self.template, |_| environment.to_string())
+    }
+}
+
+/// Requests `url` every few seconds until it answers 200, failing with the
+/// last answer once `timeout` has passed.
+pub async fn wait_until_healthy(url: &str, timeout: Duration) -> Result<()> {
+    let deadline = Instant::now() + timeout;
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        let client = reqwest::Client::builder()
+            .timeout(github::http_timeout().min(remaining.max(Duration::from_secs(1))))
+            .build()?;
+        let last = match client.get(url).send().await {
+            Ok(response) if response.status() == reqwest::StatusCode::OK => return Ok(()),
+            Ok(response) => response.status().to_string(),
+            Err(err) => format!("{}", err.without_url()),
+        };
+        if Instant::now() + CHECK_INTERVAL > deadline {
+            bail!(
+                "{} did not answer 200 within {}s (last: {})",
+                url,
+                timeout.as_secs(),
+                last
+            );
+        }
+        tokio::time::sleep(CHECK_INTERVAL).await;
+    }
+}
@@ -0,0 +1,100 @@
+use crate::template;
+use anyhow::{bail, Context, Result};
+use std::process::Stdio;
+use tokio::process::Command;
+
+/// Placeholders a post-deploy hook may use.
+const PLACEHOLDERS: &[&str] = &["env", "sha", "pr", "branch", "repo", "run_url"];
+
+/// A shell command run after each successful dispatch, e.g.
+/// `./notify.sh {env} {sha}`.
+#[derive(Debug)]
+pub struct PostHook {
+    command: String,
+    /// Whether the hook failing fails the deploy, rather than only warning.
+    pub fatal: bool,
+}
+
+/// The deploy a hook runs for.
+pub struct HookContext<'a> {
+    pub repository: &'a str,
+    pub environment: &'a str,
+    /// The commit as sent to the workflow.
+    pub commit: &'a str,
+    pub pr: Option<u64>,
+    pub branch: &'a str,
+    pub run_url: Option<&'a str>,
+}
+
+impl PostHook {
+    /// Checks that `command` only uses known placeholders.
+    pub fn parse(command: &str, fatal: bool) -> Result<PostHook> {
+        if command.trim().is_empty() {
+            bail!("The post-deploy hook is empty");
+        }
+        template::check(command, PLACEHOLDERS, "post-deploy hook")?;
+        Ok(PostHook {
+            command: command.to_string(),
+            fatal,
+        })
+    }
+
+    /// Runs the hook through the shell with each placeholder replaced by its
+    /// value, quoted so that branch names cannot inject commands. Its output
+    /// goes to stderr, indented, unless `quiet` and the hook succeeded.
+    pub async fn run(&self, deploy: &HookContext<'_>, quiet: bool) -> Result<()> {
+        let command = template::render(&self.command, |name| {
+            let value = match name {
+                "env" => deploy.environment.to_string(),
+                "sha" => deploy.commit.to_string(),
+                "pr" => deploy.pr.map(|pr| pr.to_string()).unwrap_or_default(),
+                "branch" => deploy.branch.to_string(),
+                "repo" => deploy.repository.to_string(),
+                _ => deploy.run_url.unwrap_or_default().to_string(),
+            };
+            shell_quote(&value)
+        });
+        let output = shell(&command)
+            .stdin(Stdio::null())
+            .output()
+            .await
+            .context("Failed to start the post-deploy hook")?;
+
+        let succeeded = output.status.success();
+        if !quiet || !succeeded {
+            for stream in [&output.stdout, &output.stderr] {
+                for line in String::from_utf8_lossy(stream).lines() {
+                    eprintln!("  {}", line);
+                }
+            }
+        }
+        if !succeeded {
+            bail!("The post-deploy hook failed ({})", output.status);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(not(windows))]
+fn shell(command: &str) -> Command {
+    let mut shell = Command::new("sh");
+    shell.arg("-c").arg(command);
+    shell
+}
+
+#[cfg(windows)]
+fn shell(command: &str) -> Command {
+    let mut shell = Command::new("cmd");
+    shell.arg("/C").arg(command);
+    shell
+}
+
+#[cfg(not(windows))]
+fn shell_quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', r"'\''"))
+}
+
+#[cfg(windows)]
+fn shell_quote(value: &str) -> String {
+    format!("\"{}\"", value.replace('"', "\"\""))
+}
@@ -1,11 +1,522 @@
-use anyhow::{Context, Result};
-use dialoguer::{theme::ColorfulTheme, Select};
-use serde::Deserialize;
+use anyhow::{bail, Context, Result};
+use chrono::{DateTime, NaiveDate, Utc};
+use clap::{CommandFactory, Parser, Subcommand, ValueEnum};
+use dialoguer::{theme::ColorfulTheme, Confirm, FuzzySelect, MultiSelect, Select};
+use futures::StreamExt;
+use octocrab::models::commits::{CommitComparison, GithubCommitStatus};
+use octocrab::models::pulls::PullRequest;
+use octocrab::models::repos::{Object, RepoCommit};
+use octocrab::models::workflows::Run;
+use octocrab::models::RunId;
+use octocrab::params::repos::Reference;
+use octocrab::Octocrab;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::env;
-use std::sync::Arc;
+use std::io::IsTerminal;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
+use url::Url;
+
+mod cache;
+mod checks;
+mod config;
+mod deployments;
+mod doctor;
+mod exit;
+mod github;
+mod health;
+mod hooks;
+mod notify;
+mod output;
+mod reservations;
+mod reviews;
+mod runs;
+mod state;
+mod template;
+mod watch;
+mod workflows;
+
+use cache::Cache;
+use checks::CiState;
+use config::{Config, Setting};
+use exit::Failure;
+use output::{say, Spinner};
+use reservations::{Reservation, Reservations};
+use state::{Deployment, State};
+
+/// Trigger the experimental deploy workflow for one of your open pull requests.
+///
+/// Any selection supplied as a flag skips the matching interactive prompt, so
+/// passing both `--pr` and `--env` runs without a TTY.
+#[derive(Debug, Parser)]
+#[command(version, about, after_help = exit::EXIT_CODES_HELP)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// PR to deploy as a URL (`https://github.com/org/repo/pull/123`) or
+    /// `#123`, or a branch name
+    #[arg(value_name = "PR_OR_BRANCH", conflicts_with_all = ["pr", "branch"])]
+    target: Option<String>,
+
+    /// Repository to deploy, overriding GITHUB_OWNER and GITHUB_REPO
+    #[arg(long, global = true, value_name = "OWNER/NAME", value_parser = parse_repository)]
+    repo: Option<(String, String)>,
+
+    /// Read settings from this config file only, instead of `.deploy.toml` and
+    /// the user's config file
+    #[arg(long, global = true, value_name = "FILE")]
+    config_path: Option<PathBuf>,
+
+    /// Read the GitHub token from this file; GITHUB_TOKEN still takes precedence
+    #[arg(long, global = true, value_name = "PATH")]
+    token_file: Option<PathBuf>,
+
+    /// Read the GitHub token from the first line of stdin, ahead of any other
+    /// source. Prompts then cannot read stdin, so selections must be flags
+    #[arg(long, global = true, conflicts_with = "token_file")]
+    token_stdin: bool,
+
+    /// Number of the open PR to deploy
+    #[arg(long, value_name = "NUMBER")]
+    pr: Option<u64>,
+
+    /// Offer PRs opened by this login instead of your own
+    #[arg(long, value_name = "LOGIN", conflicts_with = "branch")]
+    author: Option<String>,
+
+    /// Offer open PRs from every author
+    #[arg(long, conflicts_with_all = ["author", "branch"])]
+    all_authors: bool,
+
+    /// Only offer PRs carrying this label; repeat to require several
+    #[arg(long, value_name = "NAME", conflicts_with = "branch")]
+    label: Vec<String>,
+
+    /// Only offer PRs updated within this long, e.g. `7d`, `48h` or `2w`
+    #[arg(long, value_name = "DURATION", conflicts_with = "branch", value_parser = parse_since)]
+    since: Option<chrono::Duration>,
+
+    /// Only offer PRs targeting this base branch, e.g. `main`
+    #[arg(long, value_name = "BRANCH", conflicts_with = "branch")]
+    base: Option<String>,
+
+    /// Order of the PR list, newest first
+    #[arg(long, value_enum, default_value_t = PrSort::Updated)]
+    sort: PrSort,
+
+    /// Only offer PRs whose CI checks are passing
+    #[arg(long, conflicts_with = "branch")]
+    only_green: bool,
+
+    /// Refuse to deploy a PR without an approving review, or with changes
+    /// requested
+    #[arg(long, conflicts_with_all = ["branch", "tag"])]
+    require_approval: bool,
+
+    /// Do not offer draft PRs
+    #[arg(long, conflicts_with = "branch")]
+    no_drafts: bool,
+
+    /// Fetch and offer at most this many PRs, the most recently updated ones
+    #[arg(
+        long,
+        value_name = "N",
+        default_value_t = 50,
+        conflicts_with = "branch"
+    )]
+    limit: usize,
+
+    /// Deploy the tip of this branch instead of one of your open PRs
+    #[arg(long, value_name = "NAME", conflicts_with = "pr")]
+    branch: Option<String>,
+
+    /// Deploy the commit this tag points at, dispatching from the tag
+    #[arg(
+        long,
+        value_name = "NAME",
+        conflicts_with_all = [
+            "target", "pr", "branch", "commit", "author", "all_authors", "label", "since", "base",
+            "only_green",
+        ]
+    )]
+    tag: Option<String>,
+
+    /// Deploy this commit (full or abbreviated SHA) instead of the branch tip
+    #[arg(long, value_name = "SHA")]
+    commit: Option<String>,
+
+    /// How many of the branch's commits the commit prompt offers
+    #[arg(
+        long,
+        value_name = "N",
+        default_value_t = RECENT_COMMITS_SHOWN,
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    commit_limit: u64,
+
+    /// Request this many PRs or commits per GitHub API page, up to 100;
+    /// larger pages mean fewer round-trips for long lists
+    #[arg(
+        long,
+        value_name = "N",
+        default_value_t = DEFAULT_PER_PAGE,
+        value_parser = clap::value_parser!(u8).range(1..=100)
+    )]
+    per_page: u8,
+
+    /// Only offer commits whose author name, email or login contains this
+    #[arg(long, value_name = "TEXT", conflicts_with_all = ["commit", "tag"])]
+    commit_author: Option<String>,
+
+    /// Only offer commits whose message contains this
+    #[arg(long, value_name = "TEXT", conflicts_with_all = ["commit", "tag"])]
+    commit_grep: Option<String>,
+
+    /// Environments to deploy to, e.g. `experimental3` or `experimental1,experimental2`
+    #[arg(long, value_name = "NAME", value_delimiter = ',')]
+    env: Vec<String>,
+
+    /// Environments to deploy to by their 1-based position in the order the
+    /// prompt lists them, e.g. `3` or `1,2`
+    #[arg(
+        long,
+        value_name = "N",
+        value_delimiter = ',',
+        value_parser = clap::value_parser!(u64).range(1..),
+        conflicts_with = "env"
+    )]
+    env_index: Vec<u64>,
+
+    /// Only offer environments containing this text, or matching it as a glob
+    /// with `*` and `?`, e.g. `perf-*`; a single match is picked without asking
+    #[arg(long, value_name = "PATTERN", conflicts_with_all = ["env", "env_index"])]
+    env_filter: Option<String>,
+
+    /// Extra workflow input, parsed as JSON if it can be and sent as a string
+    /// otherwise; repeat for several
+    #[arg(long = "input", value_name = "KEY=VALUE", value_parser = parse_input)]
+    inputs: Vec<(String, serde_json::Value)>,
+
+    /// Read workflow inputs from this JSON object of strings, numbers and
+    /// booleans; `--input` flags override its entries
+    #[arg(long, value_name = "FILE")]
+    json_inputs: Option<PathBuf>,
+
+    /// Send the full 40-character commit SHA instead of the 7-character
+    /// short form
+    #[arg(long, global = true)]
+    full_sha: bool,
+
+    /// Deploy the PR's head commit, or the merge commit GitHub prepares for it
+    /// (dispatched from the default branch)
+    #[arg(long = "ref", value_enum, default_value_t = PrRef::Head, conflicts_with_all = ["branch", "commit"])]
+    pr_ref: PrRef,
+
+    /// Dispatch the workflow from this branch or tag instead of the deployed
+    /// branch; the commit still reaches it through the inputs
+    #[arg(long, value_name = "REF")]
+    workflow_ref: Option<String>,
+
+    /// Do not set a `deploy/<environment>` commit status on the deployed commit
+    #[arg(long)]
+    no_status: bool,
+
+    /// POST each deploy's result as JSON to this URL once dispatched, or once
+    /// its run finished with --wait; DEPLOY_CALLBACK_SECRET signs it
+    #[arg(long, value_name = "URL")]
+    callback_url: Option<Url>,
+
+    /// Also create a GitHub deployment for each environment, so the deploy
+    /// shows up under the repository's Environments
+    #[arg(long)]
+    create_deployment: bool,
+
+    /// Wait for the triggered workflow run to finish and exit with its result
+    #[arg(long)]
+    wait: bool,
+
+    /// After each awaited run succeeds, wait for the environment's health-check
+    /// URL to answer 200 before reporting success
+    #[arg(long, requires = "wait")]
+    health_check: bool,
+
+    /// How long --health-check waits for a 200
+    #[arg(
+        long,
+        value_name = "SECONDS",
+        default_value_t = 60,
+        requires = "health_check",
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    health_timeout: u64,
+
+    /// Show a desktop notification when each awaited run finishes
+    #[arg(long, requires = "wait")]
+    notify: bool,
+
+    /// Print the dispatch payload instead of triggering the workflow
+    #[arg(long, conflicts_with_all = ["wait", "open"])]
+    dry_run: bool,
+
+    /// Print each dispatch as an equivalent `curl` command reading the token
+    /// from $GITHUB_TOKEN; with --dry-run it is printed instead of sent
+    #[arg(long)]
+    print_curl: bool,
+
+    /// Open each triggered run, or the workflow's runs page if the run was not
+    /// found, in the browser
+    #[arg(long)]
+    open: bool,
+
+    /// Print the environments that can be deployed to, one per line, and exit
+    #[arg(long)]
+    list_environments: bool,
+
+    /// Skip confirmation prompts and answer yes to them
+    #[arg(short, long, global = true)]
+    yes: bool,
+
+    /// Do not preselect the environments and PR picked last time, nor
+    /// remember this run's picks
+    #[arg(long)]
+    no_remember: bool,
+
+    /// Fail instead of waiting when another deploy is saving the deployment
+    /// history
+    #[arg(long)]
+    no_lock_wait: bool,
+
+    /// Ignore cached results from recent runs and fetch everything again
+    #[arg(long, visible_alias = "no-cache")]
+    refresh: bool,
+
+    /// Print only errors and a final result line; never prompt
+    #[arg(short, long, global = true, conflicts_with = "verbose")]
+    quiet: bool,
+
+    /// Log each GitHub API request with its status and timing to stderr
+    #[arg(short, long, global = true)]
+    verbose: bool,
+
+    /// How to print the final result
+    #[arg(long, value_enum, default_value_t = OutputFormat::Text, global = true)]
+    output: OutputFormat,
+
+    /// Give up waiting for the run after this many seconds
+    #[arg(
+        long,
+        value_name = "SECONDS",
+        default_value_t = 600,
+        requires = "wait",
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    wait_timeout: u64,
+
+    /// Check on the run this often while waiting for it
+    #[arg(
+        long,
+        value_name = "SECONDS",
+        default_value_t = 5,
+        requires = "wait",
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    poll_interval: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum OutputFormat {
+    /// Human-readable progress and summary
+    Text,
+    /// A single JSON object on stdout; errors as `{"error": ...}` on stderr
+    Json,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum PrSort {
+    /// Most recently updated first
+    Updated,
+    /// Most recently opened first
+    Created,
+    /// Highest PR number first
+    Number,
+}
+
+impl PrSort {
+    fn sort(self, prs: &mut [PullRequest]) {
+        match self {
+            PrSort::Updated => prs.sort_by_key(|pr| std::cmp::Reverse(pr.updated_at)),
+            PrSort::Created => prs.sort_by_key(|pr| std::cmp::Reverse(pr.created_at)),
+            PrSort::Number => prs.sort_by_key(|pr| std::cmp::Reverse(pr.number)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum PrRef {
+    /// The tip of the PR's branch
+    Head,
+    /// The result of merging the PR into its base
+    Merge,
+}
+
+impl Cli {
+    /// Whether the run only reads from GitHub, so that it can go without a
+    /// token for a public repository.
+    fn is_read_only(&self) -> bool {
+        match self.command {
+            Some(Command::Watch) => true,
+            Some(Command::Reserve { .. } | Command::Release { .. }) => false,
+            // `dispatch_again` stops before dispatching or recording on a dry run
+            Some(Command::Rollback { .. } | Command::Again { .. }) => self.dry_run,
+            _ => self.dry_run,
+        }
+    }
+
+    /// Whether the run asks what to deploy, as it does unless `--env` comes
+    /// with `--pr`, `--branch` or `--tag`. Other commands may always ask.
+    fn prompts_for_selection(&self) -> bool {
+        match self.command {
+            None => {
+                self.env.is_empty()
+                    || (self.pr.is_none() && self.branch.is_none() && self.tag.is_none())
+            }
+            Some(_) => true,
+        }
+    }
+
+    /// Why this run must not prompt, if it must not.
+    fn no_prompt_reason(&self) -> Option<&'static str> {
+        if self.quiet {
+            Some("--quiet never prompts")
+        } else if self.token_stdin {
+            Some("--token-stdin leaves no stdin for prompts")
+        } else if !std::io::stdin().is_terminal() || !std::io::stderr().is_terminal() {
+            Some("there is no terminal to prompt on")
+        } else {
+            None
+        }
+    }
+}
+
+/// The result printed by `--output json`.
+#[derive(Debug, Serialize)]
+struct DeployResult<'a> {
+    branch: &'a str,
+    commit: &'a str,
+    environment: &'a str,
+    workflow_id: &'a str,
+    pr_number: Option<u64>,
+    dispatched_at: DateTime<Utc>,
+    run_url: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    deployment_id: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    duration_secs: Option<u64>,
+}
+
+/// A dispatch GitHub accepted for one of the selected environments.
+struct Triggered<'a> {
+    environment: &'a str,
+    dispatch: runs::Dispatch<'a>,
+    run: Option<Run>,
+    run_url: Option<String>,
+    /// The GitHub deployment created with `--create-deployment`.
+    deployment_id: Option<u64>,
+    /// `dispatched`, or how the run ended once waited for.
+    result: String,
+    /// Seconds from dispatch until the run completed, once waited for.
+    duration_secs: Option<u64>,
+}
+
+/// The body of a `--callback-url` request.
+#[derive(Debug, Serialize)]
+struct CallbackPayload<'a> {
+    repository: &'a str,
+    #[serde(flatten)]
+    deploy: &'a DeployResult<'a>,
+    /// `dispatched`, the run's conclusion, or `timed_out`.
+    result: &'a str,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Show the last recorded deployment of each environment and exit
+    Status,
+    /// Print a completion script for the given shell to stdout
+    Completions {
+        #[arg(value_enum)]
+        shell: clap_complete::Shell,
+    },
+    /// Check the configuration, token, repository and workflow
+    Doctor,
+    /// List past deployments of the repository, oldest first
+    History {
+        /// Only show deployments to this environment
+        #[arg(long, value_name = "NAME")]
+        env: Option<String>,
+        /// Only show the most recent deployments
+        #[arg(long, value_name = "N")]
+        limit: Option<usize>,
+    },
+    /// Show the latest workflow run of each environment, refreshing until `q`
+    Watch,
+    /// Redeploy the commit an environment ran before its latest deploy
+    Rollback {
+        /// Environment to roll back, e.g. `experimental3`
+        #[arg(long, value_name = "NAME")]
+        env: String,
+    },
+    /// Claim an environment for a while, so teammates' deploys to it are refused
+    Reserve {
+        /// Environment to reserve, e.g. `experimental3`
+        #[arg(long, value_name = "NAME")]
+        env: String,
+        /// How long to hold it, e.g. `2h` or `1d`
+        #[arg(long = "for", value_name = "DURATION", value_parser = parse_since)]
+        duration: chrono::Duration,
+    },
+    /// Give up a reservation made with `reserve`
+    Release {
+        /// Environment to release, e.g. `experimental3`
+        #[arg(long, value_name = "NAME")]
+        env: String,
+    },
+    /// Print the effective configuration and where each value comes from
+    Config,
+    /// Cancel an unfinished deploy, picked by environment or run id
+    Cancel {
+        /// Environment whose in-progress deploy to cancel, e.g. `experimental3`
+        #[arg(
+            long,
+            value_name = "NAME",
+            required_unless_present = "run_id",
+            conflicts_with = "run_id"
+        )]
+        env: Option<String>,
+        /// Id of the workflow run to cancel, as in its URL
+        #[arg(long, value_name = "ID")]
+        run_id: Option<u64>,
+    },
+    /// Pick one of the most recent deploys and dispatch it again
+    Again {
+        /// How many recent deploys to offer
+        #[arg(long, value_name = "N", default_value_t = 10)]
+        limit: usize,
+    },
+}
+
+#[derive(Debug, Deserialize)]
+struct GhHost {
+    oauth_token: Option<String>,
+}
 
 #[derive(Debug, Deserialize)]
 struct SearchResponse {
+    total_count: usize,
     items: Vec<Issue>,
 }
 
@@ -13,173 +524,4061 @@ struct SearchResponse {
 struct Issue {
     number: u64,
     pull_request: Option<PullRequestRef>,
+    #[serde(default)]
+    labels: Vec<IssueLabel>,
 }
 
 #[derive(Debug, Deserialize)]
 struct PullRequestRef {}
 
-const NUMBER_OF_EXPERIMENTAL_ENVIRONMENTS: usize = 15;
+/// An annotated tag, as returned by the git tags API.
+#[derive(Debug, Deserialize)]
+struct AnnotatedTag {
+    object: GitObject,
+}
+
+#[derive(Debug, Deserialize)]
+struct GitObject {
+    sha: String,
+    #[serde(rename = "type")]
+    kind: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct IssueLabel {
+    name: String,
+}
 
-async fn fetch_prs(
-    octocrab: Arc<octocrab::Octocrab>,
+/// Which open PRs `fetch_prs` looks for.
+#[derive(Debug, Clone)]
+struct PrSearch {
     owner: String,
     repo: String,
-    current_user: String,
-) -> Result<Vec<octocrab::models::pulls::PullRequest>> {
-    // Fetch PRs logic moved to separate function
-    let search_response = octocrab
-        .get::<SearchResponse, _, _>(
-            "/search/issues",
-            Some(&serde_json::json!({
-                "q": format!("type:pr state:open author:{} repo:{}/{}", current_user, owner, repo)
-            })),
-        )
-        .await
-        .context("Failed to fetch PRs. Please check repository name and permissions")?;
+    /// Only PRs opened by this login; `None` searches all authors.
+    author: Option<String>,
+    /// Labels a PR must carry, all of them.
+    labels: Vec<String>,
+    /// Only PRs updated on or after this day.
+    updated_since: Option<NaiveDate>,
+    /// Only PRs targeting this branch.
+    base: Option<String>,
+    /// At most this many PRs are fetched.
+    limit: usize,
+    /// Search results requested at once.
+    per_page: usize,
+}
 
-    let mut prs = Vec::new();
-    for issue in search_response.items {
-        if let Some(_pr_ref) = issue.pull_request {
-            if let Some(pr) = octocrab.pulls(&owner, &repo).get(issue.number).await.ok() {
-                prs.push(pr);
-            }
+/// The PRs a search found, cut short at its limit.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct PrList {
+    prs: Vec<PullRequest>,
+    /// More PRs matched than the limit allowed.
+    truncated: bool,
+}
+
+impl PrSearch {
+    /// The issue search query, e.g. `type:pr state:open repo:org/repo author:alice`.
+    /// Labels are matched afterwards rather than searched for.
+    fn query(&self) -> String {
+        let mut query = format!("type:pr state:open repo:{}/{}", self.owner, self.repo);
+        if let Some(author) = &self.author {
+            query.push_str(&format!(" author:{}", author));
         }
+        if let Some(since) = self.updated_since {
+            query.push_str(&format!(" updated:>={}", since.format("%Y-%m-%d")));
+        }
+        if let Some(base) = &self.base {
+            query.push_str(&format!(" base:{}", base));
+        }
+        query
+    }
+
+    /// Describes the searched PRs for messages, e.g. `alice's open PRs in org/repo`.
+    fn describe(&self) -> String {
+        let mut description = match &self.author {
+            Some(author) => format!("{}'s open PRs in {}/{}", author, self.owner, self.repo),
+            None => format!("open PRs in {}/{}", self.owner, self.repo),
+        };
+        if let Some(base) = &self.base {
+            description.push_str(&format!(" against '{}'", base));
+        }
+        if let Some(since) = self.updated_since {
+            description.push_str(&format!(" updated since {}", since));
+        }
+        description
+    }
+
+    /// Identifies the search on GitHub host `host` in the cache.
+    fn cache_key(&self, host: &str) -> String {
+        format!(
+            "{}/{}/{}?author={}&labels={}&since={}&base={}&limit={}",
+            host,
+            self.owner,
+            self.repo,
+            self.author.as_deref().unwrap_or("*"),
+            self.labels.join(","),
+            self.updated_since
+                .map(|since| since.to_string())
+                .unwrap_or_default(),
+            self.base.as_deref().unwrap_or_default(),
+            self.limit
+        )
     }
-    Ok(prs)
 }
 
-#[tokio::main]
-async fn main() -> Result<()> {
-    dotenv::dotenv().ok();
+const DEFAULT_NUMBER_OF_EXPERIMENTAL_ENVIRONMENTS: usize = 15;
+const RECENT_COMMITS_SHOWN: u64 = 10;
+/// How far back the branch is searched for commits matching `--commit-author`
+/// or `--commit-grep`.
+const MAX_COMMITS_SCANNED: usize = 1000;
+/// Results per API page unless `--per-page` says otherwise, as GitHub's own
+/// default.
+const DEFAULT_PER_PAGE: u8 = 30;
+/// How many annotated tags `resolve_tag` follows before giving up.
+const MAX_TAG_DEPTH: usize = 5;
+const SHORT_SHA_LEN: usize = 7;
+/// PR titles longer than this are cut short in the selector.
+const MAX_TITLE_CHARS: usize = 72;
+/// A PR further behind its base than this asks for confirmation; override with
+/// `DEPLOY_MAX_BEHIND`.
+const DEFAULT_MAX_COMMITS_BEHIND: i64 = 10;
+/// How far back to look for unfinished deploys to the selected environment.
+const ACTIVE_RUN_WINDOW_MINUTES: i64 = 60;
+/// How long `cancel` follows a run after asking GitHub to stop it.
+const CANCEL_WAIT: Duration = Duration::from_secs(30);
+/// How long to look for the run a dispatch created before falling back to the
+/// workflow's runs page.
+const RUN_LOOKUP_WINDOW: Duration = Duration::from_secs(15);
+/// Upper bound on search hits collected across pages.
+const MAX_SEARCH_RESULTS: usize = 500;
+/// How many HTTP timeouts the whole PR fetch, search pages and PR details
+/// included, may take before giving up.
+const PR_FETCH_DEADLINE_FACTOR: u32 = 4;
+/// PR details fetched at once; more risks GitHub's secondary rate limits.
+const PR_FETCH_CONCURRENCY: usize = 8;
 
-    // Get GitHub token from environment
-    let token = env::var("GITHUB_TOKEN").context("GITHUB_TOKEN not found in environment")?;
+/// Location of the gh CLI's `hosts.yml`, following gh's own lookup order.
+fn gh_hosts_path() -> Option<PathBuf> {
+    let config_dir = match env::var_os("GH_CONFIG_DIR") {
+        Some(dir) => PathBuf::from(dir),
+        None => match env::var_os("XDG_CONFIG_HOME") {
+            Some(dir) => PathBuf::from(dir).join("gh"),
+            None => dirs::home_dir()?.join(".config").join("gh"),
+        },
+    };
+    Some(config_dir.join("hosts.yml"))
+}
 
-    let workflow_id = env::var("DEPLOY_EXPERIMENTAL_WORKFLOW_ID")
-        .context("DEPLOY_EXPERIMENTAL_WORKFLOW_ID not found in environment")?;
+/// Reads the `oauth_token` stored by `gh auth login` for `host`.
+fn read_gh_token(path: &Path, host: &str) -> Option<String> {
+    let contents = std::fs::read_to_string(path).ok()?;
+    let hosts: HashMap<String, GhHost> = serde_yaml::from_str(&contents).ok()?;
+    hosts
+        .get(host)?
+        .oauth_token
+        .clone()
+        .filter(|token| !token.is_empty())
+}
 
-    let octocrab = octocrab::Octocrab::builder()
-        .personal_token(token)
-        .build()?;
+/// Reads the API root of a GitHub Enterprise Server instance from
+/// `GITHUB_BASE_URL`, `GITHUB_API_URL` or the config files, e.g.
+/// `https://github.example.com/api/v3`.
+fn resolve_base_url(config: &Config) -> Result<Option<Url>> {
+    let Some(config::Resolved { value, source }) = config.get(Setting::BaseUrl) else {
+        return Ok(None);
+    };
 
-    // Get organization and repo from environment
-    let owner = env::var("GITHUB_ORG").context("GITHUB_ORG not found in environment")?;
-    let repo = env::var("GITHUB_REPO").context("GITHUB_REPO not found in environment")?;
+    let url = Url::parse(value.trim())
+        .with_context(|| format!("{} is not a valid URL: '{}'", source, value))?;
+    if !matches!(url.scheme(), "http" | "https") || url.host_str().is_none() {
+        bail!(
+            "{} must be an http(s) URL with a host, e.g. https://github.example.com/api/v3, got '{}'",
+            source,
+            value
+        );
+    }
+    Ok(Some(url))
+}
 
-    println!("Authenticating with GitHub...");
+/// The host name the gh CLI stores credentials under for `base_url`.
+fn gh_host(base_url: Option<&Url>) -> &str {
+    match base_url.and_then(Url::host_str) {
+        None | Some("api.github.com") => "github.com",
+        Some(host) => host,
+    }
+}
 
-    // Get current user's login
-    println!("Fetching current user info...");
-    let current_user = octocrab
-        .current()
-        .user()
-        .await
-        .context(
-            "Failed to fetch current user. Please check your GitHub token has correct permissions",
-        )?
-        .login;
-    println!("Authenticated as: {}", current_user);
+/// Reads a token from `path`, which must hold nothing else but whitespace.
+fn read_token_file(path: &Path, source: &str) -> Result<String> {
+    let contents = std::fs::read_to_string(path).map_err(|err| {
+        exit::fail(
+            Failure::Auth,
+            format!(
+                "Failed to read the token file {} given by {}: {}",
+                path.display(),
+                source,
+                err
+            ),
+        )
+    })?;
+    let token = contents.trim();
+    if token.is_empty() {
+        return Err(exit::fail(
+            Failure::Auth,
+            format!(
+                "The token file {} given by {} is empty",
+                path.display(),
+                source
+            ),
+        ));
+    }
+    Ok(token.to_string())
+}
 
-    let octocrab = Arc::new(octocrab);
-    let pr_fetch = tokio::spawn({
-        let octocrab = Arc::clone(&octocrab);
-        let owner = owner.clone();
-        let repo = repo.clone();
-        let current_user = current_user.clone();
-        async move { fetch_prs(octocrab, owner, repo, current_user).await }
-    });
+/// The token read by `--token-stdin`, which wins over every other source.
+static STDIN_TOKEN: OnceLock<String> = OnceLock::new();
 
-    // Show environment selection while PRs are being fetched
-    let environments: Vec<String> = (1..=NUMBER_OF_EXPERIMENTAL_ENVIRONMENTS)
-        .map(|i| format!("experimental{i}"))
-        .collect();
-    let env_options: Vec<String> = environments.iter().map(|e| e.to_string()).collect();
+/// Reads the token for `--token-stdin` from the first line of stdin. This
+/// happens before any prompt, which would otherwise compete for stdin.
+fn read_stdin_token() -> Result<()> {
+    let mut line = String::new();
+    std::io::stdin().read_line(&mut line).map_err(|err| {
+        exit::fail(
+            Failure::Auth,
+            format!("Failed to read the token from stdin: {}", err),
+        )
+    })?;
+    let token = line.trim();
+    if token.is_empty() {
+        return Err(exit::fail(
+            Failure::Auth,
+            "--token-stdin was given, but stdin has no token",
+        ));
+    }
+    let _ = STDIN_TOKEN.set(token.to_string());
+    Ok(())
+}
 
-    let env_selection = Select::with_theme(&ColorfulTheme::default())
-        .with_prompt("Select environment to use")
-        .items(&env_options)
-        .default(0)
-        .interact()?;
+/// Resolves the GitHub token, preferring `--token-stdin`, then `GITHUB_TOKEN`,
+/// then a token file from `--token-file` or `GITHUB_TOKEN_FILE`, and falling
+/// back to the token saved by the gh CLI for `host`.
+fn resolve_token(host: &str, token_file: Option<&Path>) -> Result<String> {
+    match find_token(host, token_file)? {
+        Some((token, _)) => Ok(token),
+        None => Err(missing_token_error(host)),
+    }
+}
 
-    let selected_env = &environments[env_selection];
+/// The error for finding no token for `host`, listing where it was looked for.
+fn missing_token_error(host: &str) -> anyhow::Error {
+    let gh_hosts = gh_hosts_path();
+    exit::fail(
+        Failure::Auth,
+        format!(
+            "No GitHub token found. Tried:\n  - the GITHUB_TOKEN environment variable\n  - a token file from --token-file or GITHUB_TOKEN_FILE\n  - the gh CLI config for {} at {}\nSet GITHUB_TOKEN or run `gh auth login`",
+            host,
+            gh_hosts
+                .map(|path| path.display().to_string())
+                .unwrap_or_else(|| "~/.config/gh/hosts.yml".to_string())
+        ),
+    )
+}
 
-    // Wait for PR fetching to complete
-    println!("Fetching PRs from {}/{}...", owner, repo);
-    let prs = pr_fetch.await.context("PR fetch task failed")??;
+/// Like `resolve_token`, but `None` when no token is configured anywhere, and
+/// also returning where the token came from. A token file that cannot be read
+/// is still an error.
+fn find_token(host: &str, token_file: Option<&Path>) -> Result<Option<(String, String)>> {
+    TokenSources {
+        stdin: STDIN_TOKEN.get().map(String::as_str),
+        env_token: env::var("GITHUB_TOKEN").ok(),
+        token_file,
+        env_token_file: env::var_os("GITHUB_TOKEN_FILE").map(PathBuf::from),
+        gh_hosts: gh_hosts_path(),
+    }
+    .find(host)
+}
 
-    // Rest of the PR selection and workflow dispatch code remains the same
-    let pr_titles: Vec<String> = prs
-        .iter()
-        .map(|pr| {
+/// The places a token may come from, in the order `find_token` tries them.
+struct TokenSources<'a> {
+    stdin: Option<&'a str>,
+    env_token: Option<String>,
+    token_file: Option<&'a Path>,
+    env_token_file: Option<PathBuf>,
+    gh_hosts: Option<PathBuf>,
+}
+
+impl TokenSources<'_> {
+    fn find(self, host: &str) -> Result<Option<(String, String)>> {
+        if let Some(token) = self.stdin {
+            return Ok(Some((token.to_string(), "--token-stdin".to_string())));
+        }
+        if let Some(token) = self.env_token {
+            return Ok(Some((token, "GITHUB_TOKEN".to_string())));
+        }
+        if let Some(path) = self.token_file {
+            let token = read_token_file(path, "--token-file")?;
+            return Ok(Some((token, format!("--token-file {}", path.display()))));
+        }
+        if let Some(path) = &self.env_token_file {
+            let token = read_token_file(path, "GITHUB_TOKEN_FILE")?;
+            return Ok(Some((
+                token,
+                format!("GITHUB_TOKEN_FILE {}", path.display()),
+            )));
+        }
+        Ok(self.gh_hosts.and_then(|path| {
+            let token = read_gh_token(&path, host)?;
+            Some((token, format!("the gh CLI config at {}", path.display())))
+        }))
+    }
+}
+
+/// Builds the API client from the configuration: the base URL, the token as
+/// found by `resolve_token`, and the retry and timeout settings. A
+/// `read_only` run goes without a token when none is found, which public
+/// repositories allow.
+fn build_client(config: &Config, token_file: Option<&Path>, read_only: bool) -> Result<Octocrab> {
+    configure_requests()?;
+    let base_url = resolve_base_url(config)?;
+    let host = gh_host(base_url.as_ref());
+    let token = match find_token(host, token_file)? {
+        Some((token, _)) => Some(token),
+        None if read_only => {
+            eprintln!(
+                "Warning: no GitHub token found, so only public repositories can be read, under a lower rate limit"
+            );
+            None
+        }
+        None => return Err(missing_token_error(host)),
+    };
+    github_client(token, base_url.as_ref())
+}
+
+/// Builds the API client, pointed at `base_url` when talking to GitHub
+/// Enterprise Server. Without a token, requests are sent unauthenticated.
+fn github_client(token: Option<String>, base_url: Option<&Url>) -> Result<Octocrab> {
+    let mut builder = octocrab::Octocrab::builder();
+    if let Some(token) = token {
+        builder = builder.personal_token(token);
+    }
+    if let Some(base_url) = base_url {
+        builder = builder
+            .base_uri(base_url.as_str())
+            .with_context(|| format!("Invalid GitHub API URL '{}'", base_url))?;
+    }
+    Ok(builder.build()?)
+}
+
+/// Applies `DEPLOY_MAX_RETRIES` and `DEPLOY_HTTP_TIMEOUT` to the github module.
+fn configure_requests() -> Result<()> {
+    if let Ok(value) = env::var("DEPLOY_MAX_RETRIES") {
+        let retries = value.trim().parse().with_context(|| {
             format!(
-                "#{} - {}",
-                pr.number,
-                pr.title.as_ref().unwrap_or(&String::new())
+                "DEPLOY_MAX_RETRIES must be a non-negative integer, got '{}'",
+                value
+            )
+        })?;
+        github::set_max_retries(retries);
+    }
+    if let Ok(value) = env::var("DEPLOY_HTTP_TIMEOUT") {
+        let seconds: u64 = value
+            .trim()
+            .parse()
+            .ok()
+            .filter(|seconds| *seconds > 0)
+            .with_context(|| {
+                format!(
+                    "DEPLOY_HTTP_TIMEOUT must be a positive number of seconds, got '{}'",
+                    value
+                )
+            })?;
+        github::set_http_timeout(Duration::from_secs(seconds));
+    }
+    Ok(())
+}
+
+/// Parses a `--since` or `reserve --for` duration: a whole number followed by
+/// `m`, `h`, `d` or `w`.
+fn parse_since(value: &str) -> Result<chrono::Duration> {
+    let value = value.trim();
+    let split = value.len() - value.chars().last().map_or(0, char::len_utf8);
+    let (amount, unit) = value.split_at(split);
+    let amount: i64 = amount
+        .parse()
+        .ok()
+        .filter(|amount| *amount > 0)
+        .context("expected a positive number followed by m, h, d or w, e.g. 7d")?;
+    let duration = match unit {
+        "m" => chrono::Duration::try_minutes(amount),
+        "h" => chrono::Duration::try_hours(amount),
+        "d" => chrono::Duration::try_days(amount),
+        "w" => chrono::Duration::try_weeks(amount),
+        _ => bail!("unknown unit '{}'; use m, h, d or w, e.g. 7d", unit),
+    };
+    duration
+        .filter(|duration| Utc::now().checked_sub_signed(*duration).is_some())
+        .context("the duration is too long")
+}
+
+/// Parses `--repo`, which must be `owner/name`.
+fn parse_repository(value: &str) -> Result<(String, String)> {
+    match value.split_once('/') {
+        Some((owner, repo)) if config::is_valid_name(owner) && config::is_valid_name(repo) => {
+            Ok((owner.to_string(), repo.to_string()))
+        }
+        _ => bail!("expected OWNER/NAME, e.g. octocat/hello-world"),
+    }
+}
+
+/// Parses a `--input` pair; values that are not valid JSON are sent as strings.
+fn parse_input(pair: &str) -> Result<(String, serde_json::Value)> {
+    let (key, value) = pair.split_once('=').context("expected KEY=VALUE")?;
+    let key = key.trim();
+    if key.is_empty() {
+        bail!("the input name must not be empty");
+    }
+    let value = serde_json::from_str(value).unwrap_or_else(|_| value.into());
+    Ok((key.to_string(), value))
+}
+
+/// Reads `--json-inputs`: a JSON object whose values are all strings, numbers
+/// or booleans, as workflow inputs cannot be nested.
+fn read_json_inputs(path: &Path) -> Result<Vec<(String, serde_json::Value)>> {
+    let contents = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read {}", path.display()))?;
+    let value: serde_json::Value = serde_json::from_str(&contents)
+        .with_context(|| format!("Invalid JSON in {}", path.display()))?;
+    let serde_json::Value::Object(inputs) = value else {
+        bail!("{} must contain a JSON object of inputs", path.display());
+    };
+    let nested: Vec<&str> = inputs
+        .iter()
+        .filter(|(_, value)| {
+            !matches!(
+                value,
+                serde_json::Value::String(_)
+                    | serde_json::Value::Number(_)
+                    | serde_json::Value::Bool(_)
             )
         })
+        .map(|(key, _)| key.as_str())
         .collect();
+    if !nested.is_empty() {
+        bail!(
+            "Inputs {} in {} must be strings, numbers or booleans",
+            nested.join(", "),
+            path.display()
+        );
+    }
+    Ok(inputs.into_iter().collect())
+}
 
-    let selection = Select::with_theme(&ColorfulTheme::default())
-        .with_prompt("Select a PR")
-        .items(&pr_titles)
-        .default(0)
-        .interact()?;
+/// Resolves the names of the commit and environment inputs, which default to
+/// `commit_sha` and `target`.
+fn resolve_input_keys(config: &Config) -> Result<workflows::InputKeys> {
+    let mut keys = workflows::InputKeys::default();
+    for (setting, key) in [
+        (Setting::ShaInput, &mut keys.sha),
+        (Setting::TargetInput, &mut keys.target),
+    ] {
+        if let Some(resolved) = config.get(setting) {
+            let name = resolved.value.trim();
+            if name.is_empty() {
+                bail!("{} must not be empty", resolved.source);
+            }
+            *key = name.to_string();
+        }
+    }
+    if keys.sha == keys.target {
+        bail!(
+            "The commit and environment inputs must have different names, both are '{}'",
+            keys.sha
+        );
+    }
+    if let Some(resolved) = config.get(Setting::TargetTemplate) {
+        keys.target_template = workflows::TargetTemplate::parse(&resolved.value)
+            .with_context(|| format!("Invalid {}", resolved.source))?;
+    }
+    Ok(keys)
+}
 
-    let selected_pr = &prs[selection];
-    let branch_name = selected_pr.head.ref_field.clone();
+/// The configured post-deploy hook, if any, checked before anything is
+/// dispatched.
+fn resolve_post_hook(config: &Config) -> Result<Option<hooks::PostHook>> {
+    let Some(command) = config.get(Setting::PostHook) else {
+        return Ok(None);
+    };
+    let fatal = match config.get(Setting::PostHookFatal) {
+        Some(resolved) => match resolved.value.trim() {
+            "true" | "1" => true,
+            "false" | "0" => false,
+            other => bail!("{} must be true or false, got '{}'", resolved.source, other),
+        },
+        None => false,
+    };
+    hooks::PostHook::parse(&command.value, fatal)
+        .with_context(|| format!("Invalid {}", command.source))
+        .map(Some)
+}
 
-    // Get the last commit from the branch
-    let commits = octocrab
-        .repos(&owner, &repo)
-        .list_commits()
-        .branch(&branch_name)
-        .send()
-        .await?;
+/// Resolves the environments offered for deployment.
+///
+/// The picked `[[repos]]` entry's `environments` list wins, then an explicit
+/// comma-separated `DEPLOY_ENVIRONMENTS` list, then an `environments` list in a
+/// config file; otherwise `experimental1..=N` is
+/// generated, where N comes from `DEPLOY_ENVIRONMENT_COUNT` or
+/// `environment_count` in a config file.
+fn resolve_environments(config: &Config) -> Result<Vec<String>> {
+    let listed = |(environments, source): (&[String], String)| {
+        if environments.is_empty() {
+            bail!("{} contains no environment names", source);
+        }
+        Ok(environments.to_vec())
+    };
+    if let Some(repo_environments) = config.repo_environments() {
+        return listed(repo_environments);
+    }
+    if let Ok(list) = env::var("DEPLOY_ENVIRONMENTS") {
+        let environments: Vec<String> = list
+            .split(',')
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+            .map(String::from)
+            .collect();
+        if environments.is_empty() {
+            bail!("DEPLOY_ENVIRONMENTS is set but contains no environment names");
+        }
+        return Ok(environments);
+    }
+    if let Some(file_environments) = config.environments() {
+        return listed(file_environments);
+    }
 
-    let last_commit = commits
-        .items
-        .first()
-        .context("No commits found in branch")?;
+    let count = match config.get(Setting::EnvironmentCount) {
+        Some(config::Resolved { value, source }) => value
+            .trim()
+            .parse::<usize>()
+            .ok()
+            .filter(|count| *count > 0)
+            .with_context(|| format!("{} must be a positive integer, got '{}'", source, value))?,
+        None => DEFAULT_NUMBER_OF_EXPERIMENTAL_ENVIRONMENTS,
+    };
 
-    let commit_hash = last_commit.sha[..7].to_string();
+    Ok((1..=count).map(|i| format!("experimental{i}")).collect())
+}
 
-    // Trigger the GitHub Action using the proper workflow ID
-    let body = serde_json::json!({
-        "ref": branch_name,
-        "inputs": {
-            "commit_sha": commit_hash,
-            "target": format!("{}",  selected_env)
+/// Prints the environments one per line, each followed by a tab and its last
+/// recorded deployment when the repository is known and has one.
+fn list_environments(config: &Config, cli: &Cli) -> Result<()> {
+    let environments = resolve_environments(config)?;
+    let repository = match &cli.repo {
+        Some((owner, repo)) => Some(format!("{}/{}", owner, repo)),
+        None => match (config.get(Setting::Owner), config.get(Setting::Repo)) {
+            (Some(owner), Some(repo)) => Some(format!("{}/{}", owner.value, repo.value)),
+            _ => None,
+        },
+    };
+    let state = State::load();
+    let last_deployment = |env: &str| {
+        repository
+            .as_deref()
+            .and_then(|repository| state.last_deployment(repository, env))
+    };
+
+    if cli.output == OutputFormat::Json {
+        let listed: Vec<_> = environments
+            .iter()
+            .map(|env| {
+                serde_json::json!({
+                    "environment": env,
+                    "last_deployment": last_deployment(env),
+                })
+            })
+            .collect();
+        println!("{}", serde_json::to_string_pretty(&listed)?);
+        return Ok(());
+    }
+    for env in &environments {
+        match last_deployment(env) {
+            Some(deployment) => println!("{}\t{}", env, deployment.summary()),
+            None => println!("{}", env),
         }
-    });
+    }
+    Ok(())
+}
 
-    // Trigger the GitHub Action using the proper workflow ID
-    octocrab
-        .actions()
-        .create_workflow_dispatch(
-            &owner,
-            &repo,
-            workflow_id, // selected_workflow.id.to_string(),
-            &branch_name,
-        )
-        .inputs(serde_json::Value::Object(
-            body.as_object()
-                .unwrap()
-                .get("inputs")
-                .unwrap()
-                .as_object()
-                .unwrap()
-                .clone(),
-        ))
-        .send()
-        .await
-        .context(
-            "Failed to trigger workflow. Please check workflow inputs match your workflow file.",
-        )?;
+/// Prints every setting with its value and source, and the values that can
+/// only be set through the environment. The token itself is never printed.
+fn print_config(config: &Config, cli: &Cli) -> Result<()> {
+    // Rows are name, source, value: values go last, as the environment list
+    // can be long
+    let mut rows: Vec<[String; 3]> = Setting::ALL
+        .iter()
+        .map(|setting| match config.get(*setting) {
+            Some(resolved) => [setting.key().to_string(), resolved.source, resolved.value],
+            None => [setting.key().to_string(), "not set".into(), "-".into()],
+        })
+        .collect();
+
+    let environments = resolve_environments(config)?;
+    let environments_source = match (
+        config.repo_environments(),
+        env::var("DEPLOY_ENVIRONMENTS"),
+        config.environments(),
+        config.get(Setting::EnvironmentCount),
+    ) {
+        (Some((_, source)), ..) => source,
+        (None, Ok(_), ..) => "DEPLOY_ENVIRONMENTS".to_string(),
+        (None, Err(_), Some((_, source)), _) => source,
+        (None, Err(_), None, Some(count)) => format!("generated from {}", count.source),
+        (None, Err(_), None, None) => "default".to_string(),
+    };
+    rows.push([
+        "environments".into(),
+        environments_source,
+        environments.join(", "),
+    ]);
+    if let Some((groups, source)) = config.environment_groups() {
+        let names: Vec<&str> = groups.keys().map(String::as_str).collect();
+        rows.push(["groups".into(), source, names.join(", ")]);
+    }
+
+    for (name, var, default) in [
+        (
+            "http_timeout",
+            "DEPLOY_HTTP_TIMEOUT",
+            github::DEFAULT_HTTP_TIMEOUT.as_secs(),
+        ),
+        (
+            "max_retries",
+            "DEPLOY_MAX_RETRIES",
+            u64::from(github::DEFAULT_MAX_RETRIES),
+        ),
+        (
+            "cache_ttl",
+            "DEPLOY_CACHE_TTL",
+            cache::DEFAULT_TTL.as_secs(),
+        ),
+        (
+            "max_behind",
+            "DEPLOY_MAX_BEHIND",
+            DEFAULT_MAX_COMMITS_BEHIND as u64,
+        ),
+    ] {
+        rows.push(match env::var(var) {
+            Ok(value) => [name.to_string(), var.to_string(), value],
+            Err(_) => [name.to_string(), "default".to_string(), default.to_string()],
+        });
+    }
+
+    let base_url = resolve_base_url(config)?;
+    rows.push(
+        match find_token(gh_host(base_url.as_ref()), cli.token_file.as_deref()) {
+            Ok(Some((_, source))) => ["token".into(), source, "(redacted)".into()],
+            Ok(None) => ["token".into(), "not found".into(), "-".into()],
+            Err(err) => ["token".into(), github::one_line(&err), "-".into()],
+        },
+    );
+
+    let files: Vec<String> = config
+        .paths()
+        .map(|path| path.display().to_string())
+        .collect();
+    println!(
+        "Config files: {}\n",
+        if files.is_empty() {
+            "none found".to_string()
+        } else {
+            files.join(", ")
+        }
+    );
+    output::print_table(&["SETTING", "SOURCE", "VALUE"], &rows);
+    Ok(())
+}
+
+/// Resolves the configured environment groups, checking that each names only
+/// known environments. Empty when no groups are configured.
+fn resolve_environment_groups(
+    config: &Config,
+    environments: &[String],
+) -> Result<Vec<(String, Vec<String>)>> {
+    let Some((groups, source)) = config.environment_groups() else {
+        return Ok(Vec::new());
+    };
+    for (name, members) in groups {
+        if members.is_empty() {
+            bail!("Group '{}' in {} has no environments", name, source);
+        }
+        if let Some(unknown) = members.iter().find(|env| !environments.contains(env)) {
+            bail!(
+                "Group '{}' in {} lists unknown environment '{}'. Available environments: {}",
+                name,
+                source,
+                unknown,
+                environments.join(", ")
+            );
+        }
+    }
+    Ok(groups
+        .iter()
+        .map(|(name, members)| (name.clone(), members.clone()))
+        .collect())
+}
 
-    println!("Successfully triggered GitHub Action:");
-    println!("Branch: {}", branch_name);
-    println!("Commit: {}", commit_hash);
-    println!("Environment: {}", selected_env);
+/// Whether `env` matches an `--env-filter` pattern: as a glob when it has `*`
+/// or `?`, otherwise as a substring.
+fn matches_filter(env: &str, pattern: &str) -> bool {
+    if !pattern.contains(['*', '?']) {
+        return env.contains(pattern);
+    }
+    let (env, pattern): (Vec<char>, Vec<char>) = (env.chars().collect(), pattern.chars().collect());
+    // Backtracks to the last `*`, letting it absorb one more character
+    let (mut e, mut p) = (0, 0);
+    let mut star: Option<(usize, usize)> = None;
+    while e < env.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p, e));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == env[e] => {
+                e += 1;
+                p += 1;
+            }
+            _ => match star {
+                Some((star_p, star_e)) => {
+                    star = Some((star_p, star_e + 1));
+                    p = star_p + 1;
+                    e = star_e + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
 
+/// Fails unless `env` is one of `environments`.
+fn check_environment(environments: &[String], env: &str) -> Result<()> {
+    if !environments.iter().any(|known| known == env) {
+        bail!(
+            "Unknown environment '{}'. Available environments: {}",
+            env,
+            environments.join(", ")
+        );
+    }
     Ok(())
 }
+
+/// Prompts for one or more of `environments`, annotating each with its last
+/// deployment and reservation and preselecting those in `remembered`.
+fn select_environments(
+    environments: &[String],
+    state: &State,
+    reservations: &Reservations,
+    repository: &str,
+    remembered: &[String],
+) -> Result<Vec<String>> {
+    let env_items: Vec<String> = environments
+        .iter()
+        .map(|env| {
+            let mut item = match state.last_deployment(repository, env) {
+                Some(deployment) => format!("{} ({})", env, deployment.summary()),
+                None => env.clone(),
+            };
+            if let Some(reservation) = reservations.get(env) {
+                item.push_str(&format!(" [{}]", reservation.describe()));
+            }
+            item
+        })
+        .collect();
+    let defaults: Vec<bool> = environments
+        .iter()
+        .map(|env| remembered.contains(env))
+        .collect();
+    let selection = MultiSelect::with_theme(&ColorfulTheme::default())
+        .with_prompt("Select environments to deploy to (space to toggle, enter to confirm)")
+        .items(&env_items)
+        .defaults(&defaults)
+        .interact()?;
+    if selection.is_empty() {
+        bail!("No environment selected");
+    }
+    Ok(selection
+        .into_iter()
+        .map(|index| environments[index].clone())
+        .collect())
+}
+
+/// Prompts for a group, then for environments within it. The last choice
+/// offers every environment, grouped or not.
+fn select_grouped_environments(
+    groups: &[(String, Vec<String>)],
+    environments: &[String],
+    state: &State,
+    reservations: &Reservations,
+    repository: &str,
+    remembered: &[String],
+) -> Result<Vec<String>> {
+    let mut items: Vec<String> = groups
+        .iter()
+        .map(|(name, members)| format!("{} ({})", name, members.join(", ")))
+        .collect();
+    items.push("All environments".to_string());
+    let default = remembered
+        .first()
+        .and_then(|env| groups.iter().position(|(_, members)| members.contains(env)))
+        .unwrap_or(0);
+    let selection = Select::with_theme(&ColorfulTheme::default())
+        .with_prompt("Select an environment group")
+        .items(&items)
+        .default(default)
+        .interact()?;
+    let choices = groups
+        .get(selection)
+        .map_or(environments, |(_, members)| members.as_slice());
+    select_environments(choices, state, reservations, repository, remembered)
+}
+
+async fn fetch_prs(octocrab: Arc<octocrab::Octocrab>, search: PrSearch) -> Result<PrList> {
+    let query = search.query();
+    let PrSearch {
+        owner,
+        repo,
+        labels,
+        base,
+        limit,
+        per_page,
+        ..
+    } = search;
+    let mut issues = Vec::new();
+    let mut total_count = 0;
+    for page in 1.. {
+        let search_response: SearchResponse = github::get(
+            &octocrab,
+            "/search/issues",
+            Some(&serde_json::json!({
+                "q": query,
+                // Recent PRs survive the cap on results
+                "sort": "updated",
+                "order": "desc",
+                "per_page": per_page,
+                "page": page,
+            })),
+        )
+        .await
+        .map_err(|err| {
+            github::context_unless_explained(
+                err,
+                "Failed to fetch PRs. Please check repository name and permissions".to_string(),
+            )
+        })?;
+
+        let received = search_response.items.len();
+        total_count = search_response.total_count;
+        issues.extend(search_response.items);
+        // Labels are matched afterwards, so without them the limit is enough
+        if received < per_page
+            || issues.len() >= total_count
+            || issues.len() >= MAX_SEARCH_RESULTS
+            || (labels.is_empty() && issues.len() >= limit)
+        {
+            break;
+        }
+    }
+    issues.truncate(MAX_SEARCH_RESULTS);
+    issues.retain(|issue| {
+        labels
+            .iter()
+            .all(|wanted| issue.labels.iter().any(|label| &label.name == wanted))
+    });
+    let matched = if labels.is_empty() {
+        total_count.max(issues.len())
+    } else {
+        issues.len()
+    };
+    let truncated = matched > limit;
+    issues.truncate(limit);
+
+    // `buffered` keeps the search order while a few requests run at once
+    let prs: Vec<Option<PullRequest>> = futures::stream::iter(
+        issues
+            .into_iter()
+            .filter(|issue| issue.pull_request.is_some()),
+    )
+    .map(|issue| {
+        let octocrab = &octocrab;
+        let route = format!("/repos/{}/{}/pulls/{}", owner, repo, issue.number);
+        async move { github::get(octocrab, &route, None::<&()>).await.ok() }
+    })
+    .buffered(PR_FETCH_CONCURRENCY)
+    .collect()
+    .await;
+    // Check the base of each PR too, in case the search qualifier was not applied
+    let prs = prs
+        .into_iter()
+        .flatten()
+        .filter(|pr| base.as_ref().is_none_or(|base| pr.base.ref_field == *base))
+        .collect();
+    Ok(PrList { prs, truncated })
+}
+
+/// Fetches the CI state of each PR's head commit concurrently, keyed by PR
+/// number. A PR whose state cannot be read is reported as unknown.
+async fn fetch_ci_states(
+    octocrab: &Octocrab,
+    owner: &str,
+    repo: &str,
+    prs: &[PullRequest],
+) -> HashMap<u64, CiState> {
+    let states = futures::future::join_all(prs.iter().map(|pr| async move {
+        let state = checks::ci_state(octocrab, owner, repo, &pr.head.sha)
+            .await
+            .unwrap_or_else(|err| {
+                tracing::debug!("Failed to fetch checks of PR #{}: {:#}", pr.number, err);
+                CiState::Unknown
+            });
+        (pr.number, state)
+    }))
+    .await;
+    states.into_iter().collect()
+}
+
+/// What the positional argument asks to deploy.
+#[derive(Debug, PartialEq, Eq)]
+enum Target {
+    /// A PR, with the repository when it was given as a URL.
+    Pr {
+        repository: Option<(String, String)>,
+        number: u64,
+    },
+    Branch(String),
+}
+
+/// Parses the positional argument. URLs must point at a PR on `host`, the
+/// GitHub host in use.
+fn parse_target(arg: &str, host: &str) -> Result<Target> {
+    let parse_number = |number: &str| {
+        number
+            .parse::<u64>()
+            .ok()
+            .filter(|number| *number > 0)
+            .with_context(|| format!("'{}' is not a PR number", number))
+    };
+
+    if let Some(number) = arg.strip_prefix('#') {
+        return Ok(Target::Pr {
+            repository: None,
+            number: parse_number(number)?,
+        });
+    }
+    if !arg.starts_with("https://") && !arg.starts_with("http://") {
+        return Ok(Target::Branch(arg.to_string()));
+    }
+
+    let url = Url::parse(arg).with_context(|| format!("'{}' is not a valid URL", arg))?;
+    if url.host_str() != Some(host) {
+        bail!(
+            "{} is not on {}; set GITHUB_BASE_URL to deploy from another GitHub host",
+            arg,
+            host
+        );
+    }
+    let segments: Vec<&str> = url
+        .path_segments()
+        .map(|segments| segments.filter(|segment| !segment.is_empty()).collect())
+        .unwrap_or_default();
+    match segments.as_slice() {
+        [owner, repo, "pull", number, ..] => Ok(Target::Pr {
+            repository: Some((owner.to_string(), repo.to_string())),
+            number: parse_number(number)?,
+        }),
+        _ => bail!(
+            "{} is not a PR URL; expected https://{}/OWNER/REPO/pull/NUMBER",
+            arg,
+            host
+        ),
+    }
+}
+
+/// Shortens `text` to at most `max` characters, ending it with an ellipsis
+/// when anything was cut.
+fn truncate(text: &str, max: usize) -> String {
+    if text.chars().count() <= max {
+        return text.to_string();
+    }
+    let kept: String = text.chars().take(max.saturating_sub(1)).collect();
+    format!("{}…", kept.trim_end())
+}
+
+/// Whether `pr` is still a draft.
+fn is_draft(pr: &PullRequest) -> bool {
+    pr.draft == Some(true)
+}
+
+/// Says why there is no PR to choose from, which is not an error unless a PR
+/// was asked for; `select_pr` reports that one as missing.
+fn no_prs_message(
+    prs: &[PullRequest],
+    requested: Option<u64>,
+    search: &PrSearch,
+) -> Option<String> {
+    if !prs.is_empty() || requested.is_some() {
+        return None;
+    }
+    Some(if !search.labels.is_empty() {
+        format!(
+            "None of the {} carry the label(s): {}",
+            search.describe(),
+            search.labels.join(", ")
+        )
+    } else if let Some(author) = &search.author {
+        format!(
+            "No open PRs found for {} in {}/{}",
+            author, search.owner, search.repo
+        )
+    } else {
+        format!("No open PRs found in {}/{}", search.owner, search.repo)
+    })
+}
+
+/// Picks the PR to deploy, either the one requested via `--pr` or by prompting
+/// with `default` preselected. PRs with an entry in `ci_states` are marked
+/// with their CI state.
+fn select_pr(
+    mut prs: Vec<PullRequest>,
+    requested: Option<u64>,
+    default: Option<u64>,
+    search: &PrSearch,
+    ci_states: &HashMap<u64, CiState>,
+    show_authors: bool,
+) -> Result<PullRequest> {
+    let index = match requested {
+        Some(number) => prs
+            .iter()
+            .position(|pr| pr.number == number)
+            .ok_or_else(|| {
+                exit::fail(
+                    Failure::NoPr,
+                    format!("PR #{} is not one of {}", number, search.describe()),
+                )
+            })?,
+        None => {
+            let pr_titles: Vec<String> = prs
+                .iter()
+                .map(|pr| {
+                    let mut title = match pr.title.as_deref().map(str::trim) {
+                        Some(title) if !title.is_empty() => {
+                            format!("#{} - {}", pr.number, truncate(title, MAX_TITLE_CHARS))
+                        }
+                        _ => format!("#{} - (no title) [{}]", pr.number, pr.head.ref_field),
+                    };
+                    if let Some(state) = ci_states.get(&pr.number) {
+                        title = format!("{} {}", state.symbol(), title);
+                    }
+                    if is_draft(pr) {
+                        title.push_str(" [draft]");
+                    }
+                    if let Some(user) = pr.user.as_ref().filter(|_| show_authors) {
+                        title.push_str(&format!(" (@{})", user.login));
+                    }
+                    let labels: Vec<&str> = pr
+                        .labels
+                        .iter()
+                        .flatten()
+                        .map(|label| label.name.as_str())
+                        .collect();
+                    if !labels.is_empty() {
+                        title.push_str(&format!(" [{}]", labels.join(", ")));
+                    }
+                    title
+                })
+                .collect();
+
+            FuzzySelect::with_theme(&ColorfulTheme::default())
+                .with_prompt("Select a PR (type to filter)")
+                .items(&pr_titles)
+                .default(
+                    default
+                        .and_then(|number| prs.iter().position(|pr| pr.number == number))
+                        .unwrap_or(0),
+                )
+                .interact()?
+        }
+    };
+    Ok(prs.swap_remove(index))
+}
+
+/// The abbreviated form of `sha`, or all of it if it is shorter than that.
+fn short_sha(sha: &str) -> &str {
+    sha.get(..SHORT_SHA_LEN).unwrap_or(sha)
+}
+
+/// Checks that `sha` looks like a commit SHA before it is sent anywhere.
+fn validate_sha(sha: &str) -> Result<()> {
+    if sha.len() < SHORT_SHA_LEN || !sha.chars().all(|c| c.is_ascii_hexdigit()) {
+        bail!("'{}' is not a valid commit SHA", sha);
+    }
+    Ok(())
+}
+
+/// The commit value sent to the workflow, short unless `--full-sha` was given.
+fn commit_input(sha: &str, full: bool) -> Result<String> {
+    validate_sha(sha)?;
+    Ok(if full { sha } else { short_sha(sha) }.to_string())
+}
+
+/// What `--commit-author` and `--commit-grep` narrow the commit prompt to.
+/// Both match case-insensitively.
+#[derive(Debug, Default)]
+struct CommitFilter {
+    author: Option<String>,
+    message: Option<String>,
+}
+
+impl CommitFilter {
+    fn is_empty(&self) -> bool {
+        self.author.is_none() && self.message.is_none()
+    }
+
+    fn matches(&self, commit: &RepoCommit) -> bool {
+        let contains =
+            |haystack: &str, needle: &str| haystack.to_lowercase().contains(&needle.to_lowercase());
+        let author_matches = self.author.as_deref().is_none_or(|wanted| {
+            let git_author = commit.commit.author.as_ref();
+            git_author.is_some_and(|author| {
+                contains(&author.user.name, wanted) || contains(&author.user.email, wanted)
+            }) || commit
+                .author
+                .as_ref()
+                .is_some_and(|author| contains(&author.login, wanted))
+        });
+        let message_matches = self
+            .message
+            .as_deref()
+            .is_none_or(|wanted| contains(&commit.commit.message, wanted));
+        author_matches && message_matches
+    }
+}
+
+/// Lists the commits on `branch`, newest first, until `limit` of them pass
+/// `filter` or `MAX_COMMITS_SCANNED` have been read, `per_page` at a time.
+async fn fetch_commits(
+    octocrab: &Octocrab,
+    owner: &str,
+    repo: &str,
+    branch: &str,
+    filter: &CommitFilter,
+    limit: usize,
+    per_page: usize,
+) -> Result<Vec<RepoCommit>> {
+    let mut commits: Vec<RepoCommit> = Vec::new();
+    for page in 1.. {
+        let received: Vec<RepoCommit> = github::get(
+            octocrab,
+            &format!("/repos/{}/{}/commits", owner, repo),
+            Some(&serde_json::json!({
+                "sha": branch,
+                "per_page": per_page,
+                "page": page,
+            })),
+        )
+        .await
+        .map_err(|err| {
+            github::context_unless_explained(err, format!("Failed to list commits on '{}'", branch))
+        })?;
+        let full_page = received.len() == per_page;
+        commits.extend(received);
+        if !full_page
+            || commits.len() >= MAX_COMMITS_SCANNED
+            || commits.iter().filter(|c| filter.matches(c)).count() >= limit
+        {
+            break;
+        }
+    }
+    Ok(commits)
+}
+
+/// Prompts for one of the `limit` most recent commits on the branch that pass
+/// `filter`, newest first.
+fn select_commit(commits: &[RepoCommit], filter: &CommitFilter, limit: usize) -> Result<String> {
+    let recent: Vec<&RepoCommit> = commits
+        .iter()
+        .filter(|commit| filter.matches(commit))
+        .take(limit)
+        .collect();
+    if recent.is_empty() {
+        bail!(
+            "None of the last {} commits on the branch match --commit-author or --commit-grep",
+            commits.len()
+        );
+    }
+    let items: Vec<String> = recent
+        .iter()
+        .map(|commit| {
+            let mut item = format!(
+                "{} {}",
+                short_sha(&commit.sha),
+                commit.commit.message.lines().next().unwrap_or_default()
+            );
+            if let Some(author) = &commit.commit.author {
+                match author.date {
+                    Some(date) => item.push_str(&format!(
+                        " ({}, {})",
+                        author.user.name,
+                        state::format_age(date)
+                    )),
+                    None => item.push_str(&format!(" ({})", author.user.name)),
+                }
+            }
+            item
+        })
+        .collect();
+
+    let selection = Select::with_theme(&ColorfulTheme::default())
+        .with_prompt("Select a commit to deploy")
+        .items(&items)
+        .default(0)
+        .interact()?;
+    Ok(recent[selection].sha.clone())
+}
+
+/// The newest of the `commits` listed on `branch`, or the head of `pr` when
+/// none were listed.
+fn branch_tip(
+    commits: &[RepoCommit],
+    pr: Option<&PullRequest>,
+    branch: &str,
+    owner: &str,
+    repo: &str,
+) -> Result<String> {
+    match (commits.first(), pr) {
+        (Some(last_commit), _) => Ok(last_commit.sha.clone()),
+        (None, Some(pr)) => {
+            eprintln!(
+                "Warning: no commits listed on '{}'; using the head of PR #{} ({})",
+                branch, pr.number, pr.head.sha
+            );
+            Ok(pr.head.sha.clone())
+        }
+        (None, None) => bail!(
+            "No commits found on branch '{}' in {}/{}. The branch may be empty, or the token may lack read access to the repository (`repo` scope)",
+            branch,
+            owner,
+            repo
+        ),
+    }
+}
+
+/// Fetches the commit `git_ref` names, which may be a SHA, a branch or a tag.
+async fn get_commit(
+    octocrab: &Octocrab,
+    owner: &str,
+    repo: &str,
+    git_ref: &str,
+) -> Result<RepoCommit> {
+    github::get(
+        octocrab,
+        &format!(
+            "/repos/{}/{}/commits/{}",
+            owner,
+            repo,
+            github::encode_ref(git_ref)
+        ),
+        None::<&()>,
+    )
+    .await
+}
+
+/// Compares `base` with `head`, listing at most `per_page` of the commits
+/// between them.
+async fn compare_commits(
+    octocrab: &Octocrab,
+    owner: &str,
+    repo: &str,
+    base: &str,
+    head: &str,
+    per_page: Option<u8>,
+) -> Result<CommitComparison> {
+    github::get(
+        octocrab,
+        &format!(
+            "/repos/{}/{}/compare/{}...{}",
+            owner,
+            repo,
+            github::encode_ref(base),
+            github::encode_ref(head)
+        ),
+        per_page
+            .map(|per_page| serde_json::json!({ "per_page": per_page }))
+            .as_ref(),
+    )
+    .await
+}
+
+/// Resolves a full or abbreviated `sha` and checks that it is reachable from
+/// `branch`, returning the full SHA.
+async fn resolve_branch_commit(
+    octocrab: &Octocrab,
+    owner: &str,
+    repo: &str,
+    branch: &str,
+    sha: &str,
+) -> Result<String> {
+    let commit = get_commit(octocrab, owner, repo, sha)
+        .await
+        .map_err(|err| {
+            github::context_unless_explained(
+                err,
+                format!("Commit '{}' not found in {}/{}", sha, owner, repo),
+            )
+        })?;
+
+    let comparison = compare_commits(octocrab, owner, repo, &commit.sha, branch, None)
+        .await
+        .map_err(|err| {
+            github::context_unless_explained(
+                err,
+                format!("Failed to compare commit {} with '{}'", sha, branch),
+            )
+        })?;
+    if !matches!(
+        comparison.status,
+        GithubCommitStatus::Ahead | GithubCommitStatus::Identical
+    ) {
+        bail!("Commit {} is not on branch '{}'", sha, branch);
+    }
+    Ok(commit.sha)
+}
+
+/// Fails early when the repository is archived or disabled, or when the token
+/// cannot dispatch its workflows, where a dispatch would only be rejected at
+/// the end. Without `dispatching` token problems are only warned about.
+///
+/// Classic tokens report their scopes, which must include `repo`, or
+/// `public_repo` for a public repository. Fine-grained tokens do not, so the
+/// best available check is that the repository reports write access; whether
+/// the token grants "Actions: Read and write" only shows at dispatch.
+async fn check_repository_usable(
+    octocrab: &Octocrab,
+    owner: &str,
+    repo: &str,
+    dispatching: bool,
+) -> Result<()> {
+    let (repository, headers): (octocrab::models::Repository, _) =
+        github::get_with_headers(octocrab, &format!("/repos/{}/{}", owner, repo))
+            .await
+            .map_err(|err| {
+                github::context_unless_explained(
+                    err,
+                    format!(
+                        "Repository {}/{} was not found or is not accessible with this token",
+                        owner, repo
+                    ),
+                )
+            })?;
+    if repository.archived == Some(true) {
+        bail!(
+            "{}/{} is archived, so its workflows cannot be dispatched",
+            owner,
+            repo
+        );
+    }
+    if repository.disabled == Some(true) {
+        bail!(
+            "{}/{} is disabled, so its workflows cannot be dispatched",
+            owner,
+            repo
+        );
+    }
+
+    let problem = match github::oauth_scopes(&headers) {
+        Some(scopes) => {
+            let public = repository.private == Some(false);
+            let sufficient = scopes
+                .iter()
+                .any(|scope| scope == "repo" || (public && scope == "public_repo"));
+            (!sufficient).then(|| {
+                format!(
+                    "Your GitHub token's scopes ({}) do not allow dispatching workflows in {}/{}. Add the `repo` scope to it",
+                    if scopes.is_empty() { "none".to_string() } else { scopes.join(", ") },
+                    owner,
+                    repo
+                )
+            })
+        }
+        None => match &repository.permissions {
+            Some(permissions) if !permissions.push => Some(format!(
+                "Your GitHub token only has read access to {}/{}, and dispatching a workflow needs write access",
+                owner, repo
+            )),
+            _ => None,
+        },
+    };
+    match problem {
+        Some(problem) if dispatching => Err(exit::fail(Failure::Auth, problem)),
+        Some(problem) => {
+            eprintln!("Warning: {}", problem);
+            Ok(())
+        }
+        None => Ok(()),
+    }
+}
+
+/// The owner and name of the fork `pr` comes from, or `None` when its branch
+/// is in `owner/repo` itself.
+fn fork_of(pr: &PullRequest, owner: &str, repo: &str) -> Result<Option<(String, String)>> {
+    let head_repo = pr.head.repo.as_ref().with_context(|| {
+        format!(
+            "The repository PR #{} comes from was deleted, so its branch cannot be deployed",
+            pr.number
+        )
+    })?;
+    let head_owner = head_repo
+        .owner
+        .as_ref()
+        .map_or(owner, |head_owner| head_owner.login.as_str());
+    if head_owner.eq_ignore_ascii_case(owner) && head_repo.name.eq_ignore_ascii_case(repo) {
+        return Ok(None);
+    }
+    Ok(Some((head_owner.to_string(), head_repo.name.clone())))
+}
+
+/// How many commits the base branch of `pr` has that its head lacks.
+async fn commits_behind_base(
+    octocrab: &Octocrab,
+    owner: &str,
+    repo: &str,
+    pr: &PullRequest,
+) -> Result<i64> {
+    let comparison = compare_commits(
+        octocrab,
+        owner,
+        repo,
+        &pr.base.ref_field,
+        &pr.head.sha,
+        Some(1),
+    )
+    .await
+    .map_err(|err| {
+        github::context_unless_explained(
+            err,
+            format!(
+                "Failed to compare PR #{} with '{}'",
+                pr.number, pr.base.ref_field
+            ),
+        )
+    })?;
+    Ok(comparison.behind_by)
+}
+
+/// Fetches the branch or tag `reference`.
+async fn get_ref(
+    octocrab: &Octocrab,
+    owner: &str,
+    repo: &str,
+    reference: &Reference,
+) -> Result<octocrab::models::repos::Ref> {
+    github::get(
+        octocrab,
+        &format!(
+            "/repos/{}/{}/git/ref/{}",
+            owner,
+            repo,
+            github::encode_ref(&reference.ref_url())
+        ),
+        None::<&()>,
+    )
+    .await
+}
+
+/// Fails unless `branch` exists in `owner/repo`.
+async fn check_branch_exists(
+    octocrab: &Octocrab,
+    owner: &str,
+    repo: &str,
+    branch: &str,
+) -> Result<()> {
+    get_ref(
+        octocrab,
+        owner,
+        repo,
+        &Reference::Branch(branch.to_string()),
+    )
+    .await
+    .map_err(|err| {
+        github::context_unless_explained(
+            err,
+            format!(
+                "Branch '{}' not found in {}/{}. Check the name and that it has been pushed",
+                branch, owner, repo
+            ),
+        )
+    })?;
+    Ok(())
+}
+
+/// Resolves `tag` to the commit it points at, following annotated tags to
+/// their target.
+async fn resolve_tag(octocrab: &Octocrab, owner: &str, repo: &str, tag: &str) -> Result<String> {
+    let reference = get_ref(octocrab, owner, repo, &Reference::Tag(tag.to_string()))
+        .await
+        .map_err(|err| {
+            github::context_unless_explained(
+                err,
+                format!("Tag '{}' not found in {}/{}", tag, owner, repo),
+            )
+        })?;
+    let mut object = match reference.object {
+        Object::Commit { sha, .. } => return Ok(sha),
+        Object::Tag { sha, .. } => sha,
+        _ => bail!("Tag '{}' does not point at a commit", tag),
+    };
+    // A tag of a tag is allowed, if unusual
+    for _ in 0..MAX_TAG_DEPTH {
+        let annotated: AnnotatedTag = github::get(
+            octocrab,
+            &format!("/repos/{}/{}/git/tags/{}", owner, repo, object),
+            None::<&()>,
+        )
+        .await
+        .map_err(|err| {
+            github::context_unless_explained(err, format!("Failed to read tag '{}'", tag))
+        })?;
+        match annotated.object.kind.as_str() {
+            "commit" => return Ok(annotated.object.sha),
+            "tag" => object = annotated.object.sha,
+            kind => bail!("Tag '{}' points at a {}, not a commit", tag, kind),
+        }
+    }
+    bail!(
+        "Tag '{}' is nested more than {} tags deep",
+        tag,
+        MAX_TAG_DEPTH
+    )
+}
+
+/// Fetches PR `number` afresh and returns its test merge commit along with the
+/// repository's default branch.
+async fn resolve_merge_commit(
+    octocrab: &Octocrab,
+    owner: &str,
+    repo: &str,
+    number: u64,
+) -> Result<(String, String)> {
+    let pr: PullRequest = github::get(
+        octocrab,
+        &format!("/repos/{}/{}/pulls/{}", owner, repo, number),
+        None::<&()>,
+    )
+    .await
+    .map_err(|err| {
+        github::context_unless_explained(err, format!("Failed to fetch PR #{}", number))
+    })?;
+    if pr.mergeable == Some(false) {
+        bail!(
+            "PR #{} has conflicts with its base, so there is no merge commit to deploy",
+            number
+        );
+    }
+    let merge_sha = pr.merge_commit_sha.with_context(|| {
+        format!(
+            "GitHub has not prepared a merge commit for PR #{} yet; try again in a moment",
+            number
+        )
+    })?;
+    let default_branch = pr
+        .base
+        .repo
+        .and_then(|repository| repository.default_branch)
+        .with_context(|| {
+            format!(
+                "Could not determine the default branch of {}/{}",
+                owner, repo
+            )
+        })?;
+    Ok((merge_sha, default_branch))
+}
+
+/// Prompts for one of the repository's dispatchable workflows, returning its id.
+async fn select_workflow(
+    octocrab: &Octocrab,
+    owner: &str,
+    repo: &str,
+    interactive: bool,
+) -> Result<String> {
+    let workflows = workflows::dispatchable_workflows(octocrab, owner, repo).await?;
+    if workflows.is_empty() {
+        bail!(
+            "No workflow id is configured and {}/{} has no workflows with a workflow_dispatch trigger",
+            owner,
+            repo
+        );
+    }
+
+    let items: Vec<String> = workflows
+        .iter()
+        .map(|workflow| format!("{} ({})", workflow.name, workflow.path))
+        .collect();
+    if !interactive {
+        bail!(
+            "No workflow id is configured. Set DEPLOY_EXPERIMENTAL_WORKFLOW_ID or `workflow_id` in a config file to one of the dispatchable workflows in {}/{}:\n  {}",
+            owner,
+            repo,
+            items.join("\n  ")
+        );
+    }
+
+    let selection = Select::with_theme(&ColorfulTheme::default())
+        .with_prompt("Select a workflow to trigger")
+        .items(&items)
+        .default(0)
+        .interact()?;
+    Ok(workflows[selection].id.to_string())
+}
+
+/// Sends the workflow dispatch request with `body`. A request left unanswered
+/// may still have gone through, so it is only sent again once the runs show
+/// none created for `dispatch`; if they cannot be checked, it is not resent.
+async fn dispatch_workflow(
+    octocrab: &Octocrab,
+    dispatch: &runs::Dispatch<'_>,
+    body: &serde_json::Value,
+) -> Result<()> {
+    let route = format!(
+        "/repos/{}/{}/actions/workflows/{}/dispatches",
+        dispatch.owner, dispatch.repo, dispatch.workflow_id
+    );
+    let max_retries = github::max_retries();
+    let mut attempt = 0;
+    loop {
+        let err = match github::post::<(), _>(octocrab, &route, body).await {
+            Ok(()) => return Ok(()),
+            Err(err) => err,
+        };
+        if !github::is_unanswered(&err) || attempt >= max_retries {
+            return Err(err.context(
+                "Failed to trigger workflow. Please check workflow inputs match your workflow file.",
+            ));
+        }
+        eprintln!(
+            "{}; checking whether the dispatch went through",
+            github::one_line(&err)
+        );
+        match runs::locate_dispatched_run(octocrab, dispatch, RUN_LOOKUP_WINDOW).await {
+            Ok(Some(_)) => return Ok(()),
+            Ok(None) => {}
+            Err(lookup_err) => {
+                return Err(err.context(format!(
+                    "The dispatch may or may not have gone through, and checking failed ({}); look at the workflow's runs before deploying again",
+                    github::one_line(&lookup_err)
+                )))
+            }
+        }
+        attempt += 1;
+        eprintln!(
+            "No run was created; dispatching again (attempt {}/{})",
+            attempt, max_retries
+        );
+    }
+}
+
+/// The `curl` command sending the same workflow dispatch as `dispatch_workflow`,
+/// with the token left to the shell's `$GITHUB_TOKEN`.
+fn curl_command(
+    base_url: Option<&Url>,
+    owner: &str,
+    repo: &str,
+    workflow_id: &str,
+    body: &serde_json::Value,
+) -> String {
+    let api_root = base_url.map_or("https://api.github.com", |url| {
+        url.as_str().trim_end_matches('/')
+    });
+    // Single quotes keep the shell from expanding anything in the body
+    let body = body.to_string().replace('\'', r"'\''");
+    format!(
+        "curl -X POST \\\n  -H 'Accept: application/vnd.github+json' \\\n  -H \"Authorization: Bearer $GITHUB_TOKEN\" \\\n  {}/repos/{}/{}/actions/workflows/{}/dispatches \\\n  -d '{}'",
+        api_root, owner, repo, workflow_id, body
+    )
+}
+
+/// Redeploys the commit `environment` ran before its latest recorded deploy.
+async fn rollback(
+    octocrab: &Octocrab,
+    config: &Config,
+    state: &mut State,
+    owner: &str,
+    repo: &str,
+    environment: &str,
+    cli: &Cli,
+) -> Result<()> {
+    let repository = format!("{}/{}", owner, repo);
+    let current = state
+        .last_deployment(&repository, environment)
+        .with_context(|| {
+            format!(
+                "No deployments to {} in {} are recorded, so there is nothing to roll back",
+                environment, repository
+            )
+        })?;
+    let previous = state
+        .previous_deployment(&repository, environment)
+        .with_context(|| {
+            format!(
+                "No earlier successful deploy of a commit other than {} is recorded for {} in {}",
+                short_sha(&current.commit_sha),
+                environment,
+                repository
+            )
+        })?
+        .clone();
+
+    if !cli.quiet {
+        eprintln!("Rolling back {}:", environment);
+        eprintln!(
+            "  From: {} on {} ({})",
+            short_sha(&current.commit_sha),
+            current.branch,
+            current.summary()
+        );
+        eprintln!(
+            "  To:   {} on {} ({})",
+            short_sha(&previous.commit_sha),
+            previous.branch,
+            previous.summary()
+        );
+    }
+    dispatch_again(
+        octocrab,
+        config,
+        state,
+        owner,
+        repo,
+        Redeploy::Rollback(previous),
+        cli,
+    )
+    .await
+}
+
+/// Offers the latest `limit` recorded deploys in the repository, newest first,
+/// and dispatches the chosen one again.
+async fn again(
+    octocrab: &Octocrab,
+    config: &Config,
+    state: &mut State,
+    owner: &str,
+    repo: &str,
+    limit: usize,
+    cli: &Cli,
+) -> Result<()> {
+    let repository = format!("{}/{}", owner, repo);
+    let recent: Vec<Deployment> = state
+        .history(&repository, None, Some(limit))
+        .into_iter()
+        .rev()
+        .cloned()
+        .collect();
+    if recent.is_empty() {
+        bail!("No deployments in {} are recorded", repository);
+    }
+    if let Some(reason) = cli.no_prompt_reason() {
+        bail!("{}, and `deploy again` asks which deploy to repeat", reason);
+    }
+    let items: Vec<String> = recent
+        .iter()
+        .map(|d| {
+            format!(
+                "{}: {} ({}), {}",
+                d.environment,
+                d.pr_number.map_or_else(
+                    || d.branch.clone(),
+                    |n| format!("PR #{} on {}", n, d.branch)
+                ),
+                short_sha(&d.commit_sha),
+                state::format_age(d.deployed_at)
+            )
+        })
+        .collect();
+    let selection = Select::with_theme(&ColorfulTheme::default())
+        .with_prompt("Select a deploy to repeat")
+        .items(&items)
+        .default(0)
+        .interact()?;
+    let chosen = recent[selection].clone();
+    dispatch_again(
+        octocrab,
+        config,
+        state,
+        owner,
+        repo,
+        Redeploy::Again(chosen),
+        cli,
+    )
+    .await
+}
+
+/// A recorded deploy to dispatch once more.
+enum Redeploy {
+    /// What an environment ran before its latest deploy.
+    Rollback(Deployment),
+    /// A deploy picked from the history.
+    Again(Deployment),
+}
+
+impl Redeploy {
+    fn deployment(&self) -> &Deployment {
+        match self {
+            Redeploy::Rollback(deployment) | Redeploy::Again(deployment) => deployment,
+        }
+    }
+}
+
+/// What `deploy cancel` stops.
+enum CancelTarget {
+    /// The unfinished deploy to this environment.
+    Environment(String),
+    Run(RunId),
+}
+
+/// Cancels the run picked by `target` after confirming unless `--yes` was
+/// given, and reports how it ended.
+async fn cancel(
+    octocrab: &Octocrab,
+    config: &Config,
+    owner: &str,
+    repo: &str,
+    target: CancelTarget,
+    cli: &Cli,
+) -> Result<()> {
+    let run = match target {
+        CancelTarget::Run(id) => {
+            let run = runs::get_run(octocrab, owner, repo, id).await?;
+            if !run.is_active() {
+                println!(
+                    "Run {} has already finished ({}) - {}",
+                    run.id,
+                    run.conclusion.as_deref().unwrap_or(&run.status),
+                    run.html_url
+                );
+                return Ok(());
+            }
+            run
+        }
+        CancelTarget::Environment(environment) => {
+            let workflow_id = match config.get(Setting::WorkflowId) {
+                Some(id) => id.value,
+                None => {
+                    select_workflow(octocrab, owner, repo, cli.no_prompt_reason().is_none()).await?
+                }
+            };
+            let input_keys = resolve_input_keys(config)?;
+            check_run_name(octocrab, owner, repo, &workflow_id, &input_keys.target).await;
+            let mut active = runs::active_runs_for_environment(
+                octocrab,
+                owner,
+                repo,
+                &workflow_id,
+                &environment,
+                chrono::Duration::minutes(ACTIVE_RUN_WINDOW_MINUTES),
+            )
+            .await?;
+            let describe = |run: &runs::RunSummary| {
+                format!(
+                    "{} [{}] on {} started {}",
+                    run.display_title,
+                    run.status,
+                    run.head_branch,
+                    run.created_at.format("%H:%M:%S UTC")
+                )
+            };
+            match active.len() {
+                0 => {
+                    println!("No deploy to {} is in progress", environment);
+                    return Ok(());
+                }
+                1 => active.remove(0),
+                _ => {
+                    let items: Vec<String> = active.iter().map(describe).collect();
+                    if let Some(reason) = cli.no_prompt_reason() {
+                        bail!(
+                            "{} deploys to {} are in progress and {}, so pass --run-id with one of:\n  {}",
+                            active.len(),
+                            environment,
+                            reason,
+                            active
+                                .iter()
+                                .zip(&items)
+                                .map(|(run, item)| format!("{}  {}", run.id, item))
+                                .collect::<Vec<_>>()
+                                .join("\n  ")
+                        );
+                    }
+                    let selection = Select::with_theme(&ColorfulTheme::default())
+                        .with_prompt(format!("Select the deploy to {} to cancel", environment))
+                        .items(&items)
+                        .default(0)
+                        .interact()?;
+                    active.remove(selection)
+                }
+            }
+        }
+    };
+
+    if !cli.quiet {
+        eprintln!(
+            "Run {}: {} [{}] on {}, started {} - {}",
+            run.id,
+            run.display_title,
+            run.status,
+            run.head_branch,
+            run.created_at.format("%H:%M:%S UTC"),
+            run.html_url
+        );
+    }
+    if cli.dry_run {
+        say!("Dry run: not cancelling");
+        return Ok(());
+    }
+    if let Some(reason) = cli.no_prompt_reason().filter(|_| !cli.yes) {
+        bail!("{}, so pass --yes to confirm the cancellation", reason);
+    }
+    if !cli.yes {
+        let proceed = Confirm::with_theme(&ColorfulTheme::default())
+            .with_prompt("Cancel this run?")
+            .default(false)
+            .interact()?;
+        if !proceed {
+            say!("Not cancelled");
+            return Ok(());
+        }
+    }
+
+    let finished = {
+        let _spinner = Spinner::start(format!("Cancelling run {}...", run.id));
+        runs::cancel_run(octocrab, owner, repo, run.id, CANCEL_WAIT).await?
+    };
+    match finished.conclusion.as_deref() {
+        Some("cancelled") => println!("Cancelled run {} - {}", run.id, run.html_url),
+        Some(conclusion) => println!(
+            "Run {} finished with conclusion {} before it could be cancelled - {}",
+            run.id, conclusion, run.html_url
+        ),
+        None => println!(
+            "Cancellation of run {} was requested; it is still {} - {}",
+            run.id, finished.status, run.html_url
+        ),
+    }
+    Ok(())
+}
+
+/// Dispatches the deploy recorded in `redeploy` again, after confirming unless
+/// `--yes` was given, and records it.
+async fn dispatch_again(
+    octocrab: &Octocrab,
+    config: &Config,
+    state: &mut State,
+    owner: &str,
+    repo: &str,
+    redeploy: Redeploy,
+    cli: &Cli,
+) -> Result<()> {
+    let previous = redeploy.deployment().clone();
+    let environment = previous.environment.as_str();
+    let dispatch_ref = previous.dispatch_ref();
+    let workflow_id = match config.get(Setting::WorkflowId) {
+        Some(id) => id.value,
+        None => select_workflow(octocrab, owner, repo, cli.no_prompt_reason().is_none()).await?,
+    };
+    let commit = commit_input(&previous.commit_sha, cli.full_sha)?;
+    let input_keys = resolve_input_keys(config)?;
+    if input_keys.target_template.uses_pr() && previous.pr_number.is_none() {
+        bail!(
+            "The target template uses {{pr}}, but {} was deployed without a PR",
+            previous.branch
+        );
+    }
+    let inputs = serde_json::Value::from(input_keys.inputs(&workflows::DeployTarget {
+        environment,
+        commit: &commit,
+        pr: previous.pr_number,
+        branch: &previous.branch,
+    }));
+    check_workflow_inputs(
+        octocrab,
+        owner,
+        repo,
+        &workflow_id,
+        dispatch_ref,
+        &input_keys.target,
+        std::slice::from_ref(&inputs),
+    )
+    .await?;
+
+    let noun = match redeploy {
+        Redeploy::Rollback(_) => "rollback",
+        Redeploy::Again(_) => "redeploy",
+    };
+    if let Some(reason) = cli.no_prompt_reason().filter(|_| !cli.yes) {
+        bail!("{}, so pass --yes to confirm the {}", reason, noun);
+    }
+    if !cli.yes {
+        let proceed = Confirm::with_theme(&ColorfulTheme::default())
+            .with_prompt(format!("Trigger the {}?", noun))
+            .default(false)
+            .interact()?;
+        if !proceed {
+            say!("Cancelled");
+            return Ok(());
+        }
+    }
+
+    let body = serde_json::json!({
+        "ref": dispatch_ref,
+        "inputs": inputs,
+    });
+    if cli.dry_run {
+        let curl = if cli.print_curl {
+            let base_url = resolve_base_url(config)?;
+            Some(curl_command(
+                base_url.as_ref(),
+                owner,
+                repo,
+                &workflow_id,
+                &body,
+            ))
+        } else {
+            None
+        };
+        if cli.output == OutputFormat::Json {
+            let mut dry_run = serde_json::json!({
+                "repository": previous.repository,
+                "workflow_id": workflow_id,
+                "body": body,
+            });
+            if let Some(curl) = curl {
+                dry_run["curl"] = curl.into();
+            }
+            println!("{}", serde_json::to_string_pretty(&dry_run)?);
+        } else {
+            println!("Dry run, not triggering the {}.", noun);
+            println!("Repository: {}/{}", owner, repo);
+            println!("Workflow: {}", workflow_id);
+            match curl {
+                Some(curl) => println!("{}", curl),
+                None => println!("{}", serde_json::to_string_pretty(&body)?),
+            }
+        }
+        return Ok(());
+    }
+    // The run is created for the tip of the dispatched ref, which is how a
+    // retry finds it
+    let tip = get_commit(octocrab, owner, repo, dispatch_ref)
+        .await
+        .map_err(|err| {
+            github::context_unless_explained(
+                err,
+                format!("Ref '{}' not found in {}/{}", dispatch_ref, owner, repo),
+            )
+        })?;
+    let dispatched_at = Utc::now();
+    let dispatch = runs::Dispatch {
+        owner,
+        repo,
+        workflow_id: &workflow_id,
+        branch: dispatch_ref,
+        head_sha: &tip.sha,
+        dispatched_at,
+        claimed: Vec::new(),
+    };
+    state.record(Deployment {
+        deployed_at: dispatched_at,
+        result: Some("dispatching".to_string()),
+        duration_secs: None,
+        ..previous.clone()
+    });
+    if let Err(err) = state.save() {
+        eprintln!("Warning: failed to record deployment: {:#}", err);
+    }
+    let result = dispatch_workflow(octocrab, &dispatch, &body).await;
+    let outcome = if result.is_ok() {
+        "dispatched"
+    } else {
+        "failed"
+    };
+    state.set_result(&previous.repository, environment, dispatched_at, outcome);
+    if let Err(err) = state.save() {
+        eprintln!("Warning: failed to record deployment: {:#}", err);
+    }
+    result?;
+
+    if cli.output == OutputFormat::Text {
+        // The result line, printed even with --quiet
+        match redeploy {
+            Redeploy::Rollback(_) => println!(
+                "Rolled back {} to {} on {}",
+                environment,
+                short_sha(&previous.commit_sha),
+                previous.branch
+            ),
+            Redeploy::Again(_) => println!(
+                "Redeployed {} on {} to {}",
+                short_sha(&previous.commit_sha),
+                previous.branch,
+                environment
+            ),
+        }
+    } else {
+        let result = DeployResult {
+            branch: &previous.branch,
+            commit: &commit,
+            environment,
+            workflow_id: &workflow_id,
+            pr_number: previous.pr_number,
+            dispatched_at,
+            run_url: None,
+            deployment_id: None,
+            duration_secs: None,
+        };
+        println!("{}", serde_json::to_string(&result)?);
+    }
+    Ok(())
+}
+
+/// Sets a deploy's commit status on `sha`, warning rather than failing since
+/// the deploy itself went ahead.
+async fn report_deploy_status(
+    octocrab: &Octocrab,
+    owner: &str,
+    repo: &str,
+    sha: &str,
+    status: &checks::CommitStatus<'_>,
+) {
+    if let Err(err) = checks::post_status(octocrab, owner, repo, sha, status).await {
+        eprintln!(
+            "Warning: failed to set the {} commit status: {}",
+            status.context,
+            github::one_line(&err)
+        );
+    }
+}
+
+/// Adds `state` to GitHub deployment `id`, warning rather than failing like
+/// `report_deploy_status`.
+async fn report_deployment_status(
+    octocrab: &Octocrab,
+    owner: &str,
+    repo: &str,
+    id: u64,
+    state: &str,
+    log_url: Option<&str>,
+) {
+    if let Err(err) = deployments::set_status(octocrab, owner, repo, id, state, log_url).await {
+        eprintln!("Warning: {}", github::one_line(&err));
+    }
+}
+
+/// An error stating `problem` with the configured workflow, followed by the
+/// workflows that could be configured instead.
+async fn workflow_error(
+    octocrab: &Octocrab,
+    owner: &str,
+    repo: &str,
+    problem: String,
+) -> anyhow::Error {
+    let alternatives = match workflows::dispatchable_workflows(octocrab, owner, repo).await {
+        Ok(workflows) if workflows.is_empty() => format!(
+            "{}/{} has no workflows with a workflow_dispatch trigger",
+            owner, repo
+        ),
+        Ok(workflows) => format!(
+            "Set DEPLOY_EXPERIMENTAL_WORKFLOW_ID or `workflow_id` to one of the dispatchable workflows:\n  {}",
+            workflows
+                .iter()
+                .map(|workflow| format!("{} ({}), id {}", workflow.name, workflow.path, workflow.id))
+                .collect::<Vec<_>>()
+                .join("\n  ")
+        ),
+        Err(err) => format!("Listing the dispatchable workflows failed: {}", github::one_line(&err)),
+    };
+    anyhow::anyhow!("{}. {}", problem, alternatives)
+}
+
+/// Warns when the `run-name` of the workflow file at `path` does not show
+/// `target_input`, without which runs cannot be told apart by environment.
+fn warn_unless_run_name_shows(path: &str, file: &workflows::WorkflowFile, target_input: &str) {
+    if !file.run_name_shows(target_input) {
+        eprintln!(
+            "Warning: the run-name of {} does not show the `{}` input, so its runs cannot be told apart by environment; the check for deploys in progress, `watch`, `cancel --env` and the note on other environments deploying the same commit will miss them. Add e.g. `run-name: Deploy to ${{{{ inputs.{} }}}}` to the workflow",
+            path, target_input, target_input
+        );
+    }
+}
+
+/// Like the check in `check_workflow_inputs`, warns when the runs of
+/// `workflow_id` cannot be told apart by environment. The workflow file is
+/// read from the default branch; failing to read it is not reported.
+async fn check_run_name(
+    octocrab: &Octocrab,
+    owner: &str,
+    repo: &str,
+    workflow_id: &str,
+    target_input: &str,
+) {
+    let file = match workflows::get_workflow(octocrab, owner, repo, workflow_id).await {
+        Ok(workflow) => workflows::fetch_workflow_file(octocrab, owner, repo, &workflow.path, None)
+            .await
+            .map(|file| (workflow.path, file)),
+        Err(err) => Err(err),
+    };
+    match file {
+        Ok((path, file)) => warn_unless_run_name_shows(&path, &file, target_input),
+        Err(err) => tracing::debug!("Failed to read the workflow file: {:#}", err),
+    }
+}
+
+/// Fails early when the workflow is missing or disabled, or any of `inputs`,
+/// one set per dispatch, does not match what the workflow file on `branch`
+/// declares, instead of leaving GitHub to answer with a bare 404 or 422.
+/// Problems reading the workflow file only produce a warning, as does a
+/// `run-name` that does not show `target_input`.
+async fn check_workflow_inputs(
+    octocrab: &Octocrab,
+    owner: &str,
+    repo: &str,
+    workflow_id: &str,
+    branch: &str,
+    target_input: &str,
+    inputs: &[serde_json::Value],
+) -> Result<()> {
+    let workflow = match workflows::get_workflow(octocrab, owner, repo, workflow_id).await {
+        Ok(workflow) => workflow,
+        Err(err) if github::is_not_found(&err) => {
+            let problem = format!("Workflow {} not found in {}/{}", workflow_id, owner, repo);
+            return Err(workflow_error(octocrab, owner, repo, problem).await);
+        }
+        Err(err) => {
+            eprintln!("Warning: skipping workflow input validation: {:#}", err);
+            return Ok(());
+        }
+    };
+    if workflow.state != "active" {
+        let problem = format!(
+            "Workflow {} ({}) is {} and cannot be dispatched",
+            workflow.name,
+            workflow.path,
+            workflow.state.replace('_', " ")
+        );
+        return Err(workflow_error(octocrab, owner, repo, problem).await);
+    }
+    let path = workflow.path;
+    let file =
+        match workflows::fetch_workflow_file(octocrab, owner, repo, &path, Some(branch)).await {
+            Ok(file) => file,
+            Err(err) => {
+                eprintln!("Warning: skipping workflow input validation: {:#}", err);
+                return Ok(());
+            }
+        };
+
+    let Some(declared) = file.dispatch_inputs() else {
+        let problem = format!(
+            "Workflow {} on '{}' has no workflow_dispatch trigger",
+            path, branch
+        );
+        return Err(workflow_error(octocrab, owner, repo, problem).await);
+    };
+    warn_unless_run_name_shows(&path, &file, target_input);
+    for inputs in inputs {
+        let inputs = inputs
+            .as_object()
+            .context("Workflow inputs must be an object")?;
+        workflows::validate_inputs(&path, &declared, inputs)?;
+    }
+    Ok(())
+}
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    dotenv::dotenv().ok();
+    let cli = Cli::parse();
+    let json = cli.output == OutputFormat::Json;
+    output::set_quiet(json || cli.quiet);
+    if cli.verbose {
+        output::init_logging();
+    }
+    // A prompt blocks this thread, so Ctrl-C is handled on a worker thread
+    tokio::spawn(async {
+        if tokio::signal::ctrl_c().await.is_ok() {
+            exit::interrupted();
+        }
+    });
+
+    match run(cli).await {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) if exit::is_interrupted(&err) => exit::interrupted(),
+        Err(err) => {
+            if json {
+                eprintln!("{}", serde_json::json!({ "error": format!("{:#}", err) }));
+            } else {
+                eprintln!("Error: {:?}", err);
+            }
+            ExitCode::from(Failure::classify(&err).map_or(1, Failure::code))
+        }
+    }
+}
+
+async fn run(mut cli: Cli) -> Result<()> {
+    if let Some(Command::Completions { shell }) = cli.command {
+        let mut command = Cli::command();
+        let name = command.get_name().to_string();
+        clap_complete::generate(shell, &mut command, name, &mut std::io::stdout());
+        return Ok(());
+    }
+    if cli.token_stdin {
+        read_stdin_token()?;
+    }
+    // Inputs from the file go first, so that `--input` flags override them
+    if let Some(path) = &cli.json_inputs {
+        let mut inputs = read_json_inputs(path)?;
+        inputs.append(&mut cli.inputs);
+        cli.inputs = inputs;
+    }
+
+    // Get the owner and repo from the environment or config files
+    let mut config = Config::load(cli.config_path.as_deref())?;
+    if let Some((owner, repo)) = &cli.repo {
+        config.select_repo(owner, repo);
+    }
+    if let Some(Command::Doctor) = cli.command {
+        return doctor::run(&config, cli.token_file.as_deref()).await;
+    }
+    if cli.list_environments {
+        return list_environments(&config, &cli);
+    }
+    if let Some(Command::Config) = cli.command {
+        return print_config(&config, &cli);
+    }
+
+    let base_url = resolve_base_url(&config)?;
+    let host = gh_host(base_url.as_ref()).to_string();
+
+    // A positional PR or branch stands in for `--pr` or `--branch`
+    let mut target_repository = None;
+    if let Some(arg) = cli.target.take() {
+        match parse_target(&arg, &host)? {
+            Target::Pr { repository, number } => {
+                target_repository = repository;
+                cli.pr = Some(number);
+            }
+            Target::Branch(branch) => cli.branch = Some(branch),
+        }
+    }
+    // With several repositories configured, the one to deploy is picked first
+    if let Some((owner, repo)) = &target_repository {
+        config.select_repo(owner, repo);
+    } else if cli.repo.is_none() {
+        let repos = config.repos();
+        let items: Vec<String> = repos
+            .iter()
+            .map(|(owner, repo)| format!("{}/{}", owner, repo))
+            .collect();
+        let prompts = cli.prompts_for_selection() && cli.no_prompt_reason().is_none();
+        if !repos.is_empty() && prompts {
+            let selection = Select::with_theme(&ColorfulTheme::default())
+                .with_prompt("Select a repository")
+                .items(&items)
+                .default(0)
+                .interact()?;
+            let (owner, repo) = &repos[selection];
+            config.select_repo(owner, repo);
+            cli.repo = Some((owner.clone(), repo.clone()));
+        } else if !repos.is_empty()
+            && (config.get(Setting::Owner).is_none() || config.get(Setting::Repo).is_none())
+        {
+            bail!(
+                "No repository was given and this run does not prompt for one; pass --repo with one of:\n  {}",
+                items.join("\n  ")
+            );
+        }
+    }
+    // Report every missing setting at once instead of one per run
+    let offline = matches!(cli.command, Some(Command::Status | Command::History { .. }));
+    let token = find_token(&host, cli.token_file.as_deref())?.map(|(token, _)| token);
+    let has_token = token.is_some();
+    let missing_token = !offline && !cli.is_read_only() && !has_token;
+    let mut missing = Vec::new();
+    if target_repository.is_none() && cli.repo.is_none() {
+        for setting in [Setting::Owner, Setting::Repo] {
+            if config.get(setting).is_none() {
+                missing.push(setting.describe_missing());
+            }
+        }
+    }
+    if missing_token {
+        if missing.is_empty() {
+            return Err(missing_token_error(&host).context("This needs a GitHub token, as it changes the repository; --dry-run works without one for public repositories"));
+        }
+        missing.push(
+            "a GitHub token: set GITHUB_TOKEN or GITHUB_TOKEN_FILE, pass --token-file, or run `gh auth login`"
+                .to_string(),
+        );
+    }
+    if !missing.is_empty() {
+        let message = format!("Missing required settings:\n  - {}", missing.join("\n  - "));
+        return Err(if missing_token {
+            exit::fail(Failure::Auth, message)
+        } else {
+            anyhow::anyhow!(message)
+        });
+    }
+
+    let (owner, repo) = match (target_repository, cli.repo.clone()) {
+        (Some(from_url), Some(from_flag)) if from_url != from_flag => bail!(
+            "The PR URL points at {}/{} but --repo is {}/{}",
+            from_url.0,
+            from_url.1,
+            from_flag.0,
+            from_flag.1
+        ),
+        (Some((owner, repo)), _) => {
+            let configured = (config.get(Setting::Owner), config.get(Setting::Repo));
+            if let (Some(configured_owner), Some(configured_repo)) = configured {
+                if configured_owner.value != owner || configured_repo.value != repo {
+                    say!(
+                        "Using {}/{} from the PR URL instead of the configured {}/{}",
+                        owner,
+                        repo,
+                        configured_owner.value,
+                        configured_repo.value
+                    );
+                }
+            }
+            (owner, repo)
+        }
+        (None, Some(repository)) => repository,
+        (None, None) => (
+            config.require(Setting::Owner)?.value,
+            config.require(Setting::Repo)?.value,
+        ),
+    };
+    let repository = format!("{}/{}", owner, repo);
+    let environments = resolve_environments(&config)?;
+    let groups = resolve_environment_groups(&config, &environments)?;
+    for index in std::mem::take(&mut cli.env_index) {
+        let env = usize::try_from(index - 1)
+            .ok()
+            .and_then(|index| environments.get(index))
+            .with_context(|| {
+                format!(
+                    "--env-index {} is out of range; there are {} environments: {}",
+                    index,
+                    environments.len(),
+                    environments.join(", ")
+                )
+            })?;
+        cli.env.push(env.clone());
+    }
+    let mut state = State::load();
+    state::set_wait_for_lock(!cli.no_lock_wait);
+
+    if let Some(Command::Status) = cli.command {
+        state::print_status(&state, &repository, &environments);
+        return Ok(());
+    }
+    if let Some(Command::History { env, limit }) = &cli.command {
+        let history = state.history(&repository, env.as_deref(), *limit);
+        if cli.output == OutputFormat::Json {
+            println!("{}", serde_json::to_string_pretty(&history)?);
+        } else if history.is_empty() {
+            println!("No deployments in {} are recorded", repository);
+        } else {
+            state::print_history(&history);
+        }
+        return Ok(());
+    }
+
+    let cache_ttl = match env::var("DEPLOY_CACHE_TTL") {
+        Ok(value) => Duration::from_secs(value.trim().parse().with_context(|| {
+            format!(
+                "DEPLOY_CACHE_TTL must be a number of seconds, got '{}'",
+                value
+            )
+        })?),
+        Err(_) => cache::DEFAULT_TTL,
+    };
+    let mut cache = Cache::load(cache_ttl);
+    let max_behind = match env::var("DEPLOY_MAX_BEHIND") {
+        Ok(value) => value
+            .trim()
+            .parse::<i64>()
+            .ok()
+            .filter(|n| *n >= 0)
+            .with_context(|| {
+                format!(
+                    "DEPLOY_MAX_BEHIND must be a number of commits, got '{}'",
+                    value
+                )
+            })?,
+        Err(_) => DEFAULT_MAX_COMMITS_BEHIND,
+    };
+    let octocrab = build_client(&config, cli.token_file.as_deref(), cli.is_read_only())?;
+    let dispatching = !cli.dry_run && !matches!(cli.command, Some(Command::Watch));
+    check_repository_usable(&octocrab, &owner, &repo, dispatching)
+        .await
+        .map_err(|err| {
+            if has_token || !github::is_not_found(&err) {
+                return err;
+            }
+            err.context("No GitHub token was found, so only public repositories can be read")
+        })?;
+
+    if let Some(Command::Rollback { env }) = &cli.command {
+        check_environment(&environments, env)?;
+        return rollback(&octocrab, &config, &mut state, &owner, &repo, env, &cli).await;
+    }
+    if let Some(Command::Again { limit }) = &cli.command {
+        return again(&octocrab, &config, &mut state, &owner, &repo, *limit, &cli).await;
+    }
+    if let Some(Command::Cancel { env, run_id }) = &cli.command {
+        if let Some(env) = env {
+            check_environment(&environments, env)?;
+        }
+        let target = match run_id {
+            Some(id) => CancelTarget::Run(RunId(*id)),
+            None => CancelTarget::Environment(env.clone().unwrap_or_default()),
+        };
+        return cancel(&octocrab, &config, &owner, &repo, target, &cli).await;
+    }
+    if let Some(Command::Watch) = cli.command {
+        let workflow_id = match config.get(Setting::WorkflowId) {
+            Some(id) => id.value,
+            None => {
+                select_workflow(&octocrab, &owner, &repo, cli.no_prompt_reason().is_none()).await?
+            }
+        };
+        let input_keys = resolve_input_keys(&config)?;
+        check_run_name(&octocrab, &owner, &repo, &workflow_id, &input_keys.target).await;
+        return watch::run(&octocrab, &owner, &repo, &workflow_id, &environments).await;
+    }
+
+    if has_token {
+        say!("Authenticating with GitHub...");
+    }
+    // Get current user's login, unless a recent run already did. Without a
+    // token there is none, and every reservation is someone else's
+    let cached_user = token
+        .as_deref()
+        .and_then(|token| cache.user(&host, token))
+        .filter(|_| !cli.refresh);
+    let current_user = match (token.as_deref(), cached_user) {
+        (None, _) => String::new(),
+        (Some(_), Some(login)) => login,
+        (Some(token), None) => {
+            say!("Fetching current user info...");
+            let login = github::get::<octocrab::models::Author, _>(&octocrab, "/user", None::<&()>)
+                .await
+                .map_err(|err| {
+                    github::context_unless_explained(
+                        err,
+                        "Failed to fetch current user. Please check your GitHub token has correct permissions".to_string(),
+                    )
+                })?
+                .login;
+            cache.store_user(&host, token, &login);
+            login
+        }
+    };
+    if has_token {
+        say!("Authenticated as: {}", current_user);
+    }
+
+    if let Some(Command::Reserve { env, duration }) = &cli.command {
+        check_environment(&environments, env)?;
+        let mut reservations = Reservations::load(&octocrab, &owner, &repo).await?;
+        if let Some(existing) = reservations.held_by_other(env, &current_user) {
+            if !cli.yes {
+                bail!(
+                    "{} is already {}. Pass --yes to take it over",
+                    env,
+                    existing.describe()
+                );
+            }
+            eprintln!("Warning: taking over {} from {}", env, existing.owner);
+        }
+        let reservation = Reservation {
+            owner: current_user.clone(),
+            until: Utc::now() + *duration,
+        };
+        let description = reservation.describe();
+        reservations.reserve(env, reservation);
+        reservations.save(&octocrab, &owner, &repo).await?;
+        println!("{} is now {}", env, description);
+        return Ok(());
+    }
+    if let Some(Command::Release { env }) = &cli.command {
+        check_environment(&environments, env)?;
+        let mut reservations = Reservations::load(&octocrab, &owner, &repo).await?;
+        let Some(released) = reservations.release(env) else {
+            println!("{} is not reserved", env);
+            return Ok(());
+        };
+        if released.owner != current_user {
+            eprintln!("Warning: releasing {}'s reservation", released.owner);
+        }
+        reservations.save(&octocrab, &owner, &repo).await?;
+        println!("Released {}", env);
+        return Ok(());
+    }
+    // Reservations only guard deploys, so failing to read them is no reason to stop
+    let reservations = Reservations::load(&octocrab, &owner, &repo)
+        .await
+        .unwrap_or_else(|err| {
+            eprintln!("Warning: {}", github::one_line(&err));
+            Reservations::default()
+        });
+
+    // With both selections supplied as flags the run must not prompt at all
+    let interactive = cli.prompts_for_selection();
+    if let Some(reason) = cli.no_prompt_reason().filter(|_| interactive) {
+        bail!("{}, so pass --env along with --pr or --branch", reason);
+    }
+    let commit_filter = CommitFilter {
+        author: cli.commit_author.clone(),
+        message: cli.commit_grep.clone(),
+    };
+    if !interactive && !commit_filter.is_empty() {
+        bail!("--commit-author and --commit-grep narrow the commit prompt, which is skipped when --env is given along with --pr or --branch; pass --commit instead");
+    }
+    let commit_limit = usize::try_from(cli.commit_limit).unwrap_or(usize::MAX);
+    let per_page = usize::from(cli.per_page);
+
+    let octocrab = Arc::new(octocrab);
+    // PRs are only needed when no branch was given explicitly
+    let pr_search = PrSearch {
+        owner: owner.clone(),
+        repo: repo.clone(),
+        author: match (&cli.author, cli.all_authors) {
+            (_, true) => None,
+            (Some(author), false) => Some(author.clone()),
+            // A PR asked for by number is found among everyone's
+            (None, false) if !has_token && cli.pr.is_some() => None,
+            (None, false) if !has_token && cli.branch.is_none() && cli.tag.is_none() => bail!(
+                "Without a GitHub token there is no current user whose PRs to list; pass --author <login>, --all-authors or --pr"
+            ),
+            (None, false) => Some(current_user.clone()),
+        },
+        labels: cli.label.clone(),
+        // Whole days keep the search, and so the cache key, stable within a day
+        updated_since: cli.since.map(|since| (Utc::now() - since).date_naive()),
+        base: cli.base.clone(),
+        limit: cli.limit,
+        per_page,
+    };
+    let cached_prs = cache
+        .prs(&pr_search.cache_key(&host))
+        .filter(|_| !cli.refresh);
+    let pr_fetch = (cli.branch.is_none() && cli.tag.is_none() && cached_prs.is_none()).then(|| {
+        tokio::spawn({
+            let octocrab = Arc::clone(&octocrab);
+            let search = pr_search.clone();
+            async move {
+                let deadline = github::http_timeout() * PR_FETCH_DEADLINE_FACTOR;
+                tokio::time::timeout(deadline, fetch_prs(octocrab, search))
+                    .await
+                    .unwrap_or_else(|_| {
+                        bail!(
+                            "Timed out after {}s fetching PRs; GitHub may be unreachable",
+                            deadline.as_secs()
+                        )
+                    })
+            }
+        })
+    });
+
+    // A configured id pins the workflow; otherwise pick one of the dispatchable ones
+    let workflow_id = match config.get(Setting::WorkflowId) {
+        Some(id) => id.value,
+        None => select_workflow(&octocrab, &owner, &repo, interactive).await?,
+    };
+
+    // Show environment selection while PRs are being fetched
+    let selected_envs: Vec<String> = if cli.env.is_empty() {
+        let remembered = if cli.no_remember {
+            &[]
+        } else {
+            state.last_selection(&repository)
+        };
+        if let Some(pattern) = &cli.env_filter {
+            let matching: Vec<String> = environments
+                .iter()
+                .filter(|env| matches_filter(env, pattern))
+                .cloned()
+                .collect();
+            match matching.as_slice() {
+                [] => bail!(
+                    "No environment matches '{}'. Available environments: {}",
+                    pattern,
+                    environments.join(", ")
+                ),
+                [only] => {
+                    say!(
+                        "Selected {}, the only environment matching '{}'",
+                        only,
+                        pattern
+                    );
+                    matching
+                }
+                _ => {
+                    select_environments(&matching, &state, &reservations, &repository, remembered)?
+                }
+            }
+        } else if groups.is_empty() {
+            select_environments(
+                &environments,
+                &state,
+                &reservations,
+                &repository,
+                remembered,
+            )?
+        } else {
+            select_grouped_environments(
+                &groups,
+                &environments,
+                &state,
+                &reservations,
+                &repository,
+                remembered,
+            )?
+        }
+    } else {
+        let unknown: Vec<&str> = cli
+            .env
+            .iter()
+            .filter(|env| !environments.contains(env))
+            .map(String::as_str)
+            .collect();
+        if !unknown.is_empty() {
+            bail!(
+                "Unknown environment(s) {}. Available environments: {}",
+                unknown.join(", "),
+                environments.join(", ")
+            );
+        }
+        let mut selected: Vec<String> = Vec::new();
+        for env in &cli.env {
+            if !selected.contains(env) {
+                selected.push(env.clone());
+            }
+        }
+        selected
+    };
+
+    let reserved: Vec<String> = selected_envs
+        .iter()
+        .filter_map(|env| {
+            reservations
+                .held_by_other(env, &current_user)
+                .map(|reservation| format!("{} ({})", env, reservation.describe()))
+        })
+        .collect();
+    if !reserved.is_empty() {
+        let reserved = reserved.join(", ");
+        if cli.yes {
+            eprintln!(
+                "Warning: deploying to {} anyway because --yes was given",
+                reserved
+            );
+        } else if !interactive {
+            bail!(
+                "Refusing to deploy to {}. Pass --yes to deploy anyway",
+                reserved
+            );
+        } else {
+            let proceed = Confirm::with_theme(&ColorfulTheme::default())
+                .with_prompt(format!("Deploy to {} anyway?", reserved))
+                .default(false)
+                .interact()?;
+            if !proceed {
+                say!("Cancelled");
+                return Ok(());
+            }
+        }
+    }
+
+    // A tag stands in for the branch, with its commit as the tip
+    let mut tag_sha = None;
+    let (selected_pr, branch_name) = if let Some(tag) = cli.tag.clone() {
+        tag_sha = Some(resolve_tag(&octocrab, &owner, &repo, &tag).await?);
+        (None, tag)
+    } else if let Some(branch) = cli.branch {
+        check_branch_exists(&octocrab, &owner, &repo, &branch).await?;
+        (None, branch)
+    } else {
+        let PrList { mut prs, truncated } = match cached_prs {
+            Some(prs) => prs,
+            None => {
+                // Wait for PR fetching to complete
+                let prs = {
+                    let _spinner =
+                        Spinner::start(format!("Fetching PRs from {}/{}...", owner, repo));
+                    pr_fetch
+                        .expect("PRs are fetched when no branch is given")
+                        .await
+                        .context("PR fetch task failed")??
+                };
+                cache.store_prs(&pr_search.cache_key(&host), prs.clone());
+                prs
+            }
+        };
+        if let Some(message) = no_prs_message(&prs, cli.pr, &pr_search) {
+            println!("{}", message);
+            return Ok(());
+        }
+        if truncated {
+            say!(
+                "Showing the {} most recently updated {}; pass --limit to see more",
+                cli.limit,
+                pr_search.describe()
+            );
+        }
+        if cli.no_drafts {
+            if let Some(number) = cli.pr {
+                if prs.iter().any(|pr| pr.number == number && is_draft(pr)) {
+                    return Err(exit::fail(
+                        Failure::NoPr,
+                        format!("PR #{} is a draft", number),
+                    ));
+                }
+            }
+            prs.retain(|pr| !is_draft(pr));
+            if prs.is_empty() {
+                println!("All of the {} are drafts", pr_search.describe());
+                return Ok(());
+            }
+        }
+
+        // CI states are only needed to annotate the prompt or to filter
+        let ci_states = if cli.only_green || cli.pr.is_none() {
+            fetch_ci_states(&octocrab, &owner, &repo, &prs).await
+        } else {
+            HashMap::new()
+        };
+        if cli.only_green {
+            let passing = |pr: &PullRequest| ci_states.get(&pr.number) == Some(&CiState::Passing);
+            if let Some(number) = cli.pr {
+                if prs.iter().any(|pr| pr.number == number && !passing(pr)) {
+                    return Err(exit::fail(
+                        Failure::NoPr,
+                        format!("PR #{} does not have passing checks", number),
+                    ));
+                }
+            }
+            prs.retain(passing);
+            if prs.is_empty() {
+                println!("None of the {} have passing checks", pr_search.describe());
+                return Ok(());
+            }
+        }
+        let last_pr = (!cli.no_remember)
+            .then(|| state.last_deployed_pr(&repository))
+            .flatten();
+        cli.sort.sort(&mut prs);
+        // Only someone else's PRs need their author shown
+        let show_authors = cli.all_authors || cli.author.is_some();
+        let pr = select_pr(prs, cli.pr, last_pr, &pr_search, &ci_states, show_authors)?;
+        // A draft is usually not ready to deploy, so make sure it was meant
+        if is_draft(&pr) {
+            eprintln!("Warning: PR #{} is a draft", pr.number);
+            if interactive && !cli.yes {
+                let proceed = Confirm::with_theme(&ColorfulTheme::default())
+                    .with_prompt("Deploy it anyway?")
+                    .default(false)
+                    .interact()?;
+                if !proceed {
+                    say!("Cancelled");
+                    return Ok(());
+                }
+            }
+        }
+        let branch = pr.head.ref_field.clone();
+        (Some(pr), branch)
+    };
+    if let Err(err) = cache.save() {
+        tracing::debug!("Failed to save the cache: {:#}", err);
+    }
+
+    if cli.require_approval && selected_pr.is_none() {
+        bail!("--require-approval needs a PR to deploy, not a branch");
+    }
+    if let Some(pr) = selected_pr.as_ref().filter(|_| cli.require_approval) {
+        let reviews = reviews::review_state(&octocrab, &owner, &repo, pr.number).await?;
+        if let Some(refusal) = reviews.refusal() {
+            bail!(
+                "Refusing to deploy PR #{} because {}, and --require-approval was given",
+                pr.number,
+                refusal
+            );
+        }
+        say!(
+            "PR #{} is approved by {}",
+            pr.number,
+            reviews.approved_by.join(", ")
+        );
+    }
+
+    // A stale branch would deploy without recent changes from its base
+    if let Some(pr) = &selected_pr {
+        match commits_behind_base(&octocrab, &owner, &repo, pr).await {
+            Ok(behind) if behind > max_behind => {
+                eprintln!(
+                    "Warning: '{}' is {} commits behind '{}'",
+                    branch_name, behind, pr.base.ref_field
+                );
+                if interactive && !cli.yes {
+                    let proceed = Confirm::with_theme(&ColorfulTheme::default())
+                        .with_prompt("Deploy it anyway?")
+                        .default(false)
+                        .interact()?;
+                    if !proceed {
+                        say!("Cancelled");
+                        return Ok(());
+                    }
+                }
+            }
+            Ok(_) => {}
+            Err(err) => eprintln!("Warning: {}", github::one_line(&err)),
+        }
+    }
+
+    // A fork's branch only exists in the fork, so its commits are read there
+    // while the workflow still runs in this repository
+    let mut dispatch_ref = cli.workflow_ref.clone();
+    let fork = match &selected_pr {
+        Some(pr) => fork_of(pr, &owner, &repo)?,
+        None => None,
+    };
+    if let (Some((fork_owner, fork_repo)), Some(pr)) = (&fork, &selected_pr) {
+        // The branch cannot be dispatched from, so run the base branch's workflow
+        dispatch_ref.get_or_insert_with(|| pr.base.ref_field.clone());
+        eprintln!(
+            "Warning: PR #{} comes from the fork {}/{}. Its code will run with this repository's secrets, so review it before deploying",
+            pr.number, fork_owner, fork_repo
+        );
+        if interactive && !cli.yes {
+            let proceed = Confirm::with_theme(&ColorfulTheme::default())
+                .with_prompt("Deploy it anyway?")
+                .default(false)
+                .interact()?;
+            if !proceed {
+                say!("Cancelled");
+                return Ok(());
+            }
+        }
+    }
+    let (branch_owner, branch_repo) = fork.clone().unwrap_or((owner.clone(), repo.clone()));
+
+    // Get the last commit from the branch
+    let commits: Vec<RepoCommit> = if tag_sha.is_some() {
+        Vec::new()
+    } else {
+        let _spinner = Spinner::start(format!("Fetching commits on {}...", branch_name));
+        fetch_commits(
+            &octocrab,
+            &branch_owner,
+            &branch_repo,
+            &branch_name,
+            &commit_filter,
+            commit_limit,
+            per_page,
+        )
+        .await?
+    };
+
+    // Runs are created for the branch tip, whichever commit is deployed
+    let head_sha = match tag_sha {
+        Some(sha) => sha,
+        None => branch_tip(&commits, selected_pr.as_ref(), &branch_name, &owner, &repo)?,
+    };
+
+    // A merge commit is not on the PR's branch, so it is dispatched from the
+    // default branch unless another ref was asked for
+    let deploy_sha = match (&cli.commit, cli.pr_ref, &selected_pr) {
+        (_, PrRef::Merge, None) => bail!("--ref merge needs a PR to deploy, not a branch"),
+        (_, PrRef::Merge, Some(pr)) => {
+            let (merge_sha, default_branch) =
+                resolve_merge_commit(&octocrab, &owner, &repo, pr.number).await?;
+            dispatch_ref.get_or_insert(default_branch);
+            merge_sha
+        }
+        (Some(sha), ..) => {
+            resolve_branch_commit(&octocrab, &branch_owner, &branch_repo, &branch_name, sha).await?
+        }
+        (None, ..) if interactive && (commits.len() > 1 || !commit_filter.is_empty()) => {
+            select_commit(&commits, &commit_filter, commit_limit)?
+        }
+        (None, ..) => head_sha.clone(),
+    };
+    let commit_hash = commit_input(&deploy_sha, cli.full_sha)?;
+
+    // The run is created for the tip of the ref the workflow is dispatched from
+    let (workflow_ref, workflow_head_sha) = match &dispatch_ref {
+        Some(workflow_ref) => {
+            let tip = get_commit(&octocrab, &owner, &repo, workflow_ref)
+                .await
+                .map_err(|err| {
+                    github::context_unless_explained(
+                        err,
+                        format!(
+                            "Workflow ref '{}' not found in {}/{}",
+                            workflow_ref, owner, repo
+                        ),
+                    )
+                })?;
+            (workflow_ref.clone(), tip.sha)
+        }
+        None => (branch_name.clone(), head_sha.clone()),
+    };
+
+    let input_keys = resolve_input_keys(&config)?;
+    let post_hook = resolve_post_hook(&config)?;
+    let health_check = if cli.health_check {
+        let template = config.require(Setting::HealthCheckUrl)?;
+        let check = health::HealthCheck::parse(&template.value)
+            .with_context(|| format!("Invalid {}", template.source))?;
+        Some(check)
+    } else {
+        None
+    };
+    if input_keys.target_template.uses_pr() && selected_pr.is_none() {
+        bail!("The target template uses {{pr}}, so deploy a PR rather than a branch or tag");
+    }
+    for (key, _) in &cli.inputs {
+        if *key == input_keys.sha || *key == input_keys.target {
+            eprintln!(
+                "Warning: input {} overrides the value the tool would send",
+                key
+            );
+        }
+    }
+    let dispatch_body = |environment: &str| {
+        let mut inputs = input_keys.inputs(&workflows::DeployTarget {
+            environment,
+            commit: &commit_hash,
+            pr: selected_pr.as_ref().map(|pr| pr.number),
+            branch: &branch_name,
+        });
+        inputs.extend(cli.inputs.iter().cloned());
+        serde_json::json!({
+            "ref": workflow_ref,
+            "inputs": inputs,
+        })
+    };
+
+    // Environments differ in the target input, which may be a choice
+    let env_inputs: Vec<serde_json::Value> = selected_envs
+        .iter()
+        .map(|env| dispatch_body(env)["inputs"].clone())
+        .collect();
+    check_workflow_inputs(
+        &octocrab,
+        &owner,
+        &repo,
+        &workflow_id,
+        &workflow_ref,
+        &input_keys.target,
+        &env_inputs,
+    )
+    .await?;
+
+    if cli.dry_run {
+        if cli.output != OutputFormat::Json {
+            println!("Dry run, not triggering the workflow.");
+            println!("Repository: {}/{}", owner, repo);
+            println!("Workflow: {}", workflow_id);
+        }
+        for env in &selected_envs {
+            let body = dispatch_body(env);
+            let curl = cli
+                .print_curl
+                .then(|| curl_command(base_url.as_ref(), &owner, &repo, &workflow_id, &body));
+            if cli.output == OutputFormat::Json {
+                let mut dry_run = serde_json::json!({
+                    "repository": repository,
+                    "workflow_id": workflow_id,
+                    "body": body,
+                });
+                if let Some(curl) = curl {
+                    dry_run["curl"] = curl.into();
+                }
+                println!("{}", serde_json::to_string_pretty(&dry_run)?);
+            } else if let Some(curl) = curl {
+                println!("{}", curl);
+            } else {
+                println!("{}", serde_json::to_string_pretty(&body)?);
+            }
+        }
+        return Ok(());
+    }
+
+    // The same commit still deploying elsewhere may be intended, or a mix-up
+    match runs::active_runs_for_commit(
+        &octocrab,
+        &owner,
+        &repo,
+        &workflow_id,
+        &workflow_ref,
+        &workflow_head_sha,
+    )
+    .await
+    {
+        Ok(in_flight) => {
+            let elsewhere: Vec<(&String, &runs::RunSummary)> = environments
+                .iter()
+                .filter(|env| !selected_envs.contains(env))
+                .filter_map(|env| Some((env, in_flight.iter().find(|run| run.targets(env))?)))
+                .collect();
+            if !elsewhere.is_empty() {
+                let what = match &selected_pr {
+                    Some(pr) => format!("PR #{}", pr.number),
+                    None => branch_name.clone(),
+                };
+                say!("Note: {} is also being deployed to:", what);
+                for (env, run) in elsewhere {
+                    say!("  {} [{}] - {}", env, run.status, run.html_url);
+                }
+            }
+        }
+        Err(err) => eprintln!(
+            "Warning: could not check for other deploys of this commit: {}",
+            github::one_line(&err)
+        ),
+    }
+
+    let mut busy = Vec::new();
+    for env in &selected_envs {
+        let active_runs = runs::active_runs_for_environment(
+            &octocrab,
+            &owner,
+            &repo,
+            &workflow_id,
+            env,
+            chrono::Duration::minutes(ACTIVE_RUN_WINDOW_MINUTES),
+        )
+        .await?;
+        if active_runs.is_empty() {
+            continue;
+        }
+        eprintln!("{} already has a deploy in progress:", env);
+        for run in &active_runs {
+            eprintln!(
+                "  {} [{}] on {} started {} - {}",
+                run.display_title,
+                run.status,
+                run.head_branch,
+                run.created_at.format("%H:%M:%S UTC"),
+                run.html_url
+            );
+        }
+        busy.push(env.as_str());
+    }
+    if !busy.is_empty() {
+        let busy = busy.join(", ");
+        if cli.yes {
+            eprintln!("Deploying anyway because --yes was given");
+        } else if !interactive {
+            bail!(
+                "Refusing to deploy to {} while another deploy is in progress. Pass --yes to deploy anyway",
+                busy
+            );
+        } else {
+            let proceed = Confirm::with_theme(&ColorfulTheme::default())
+                .with_prompt(format!("Deploy to {} anyway?", busy))
+                .default(false)
+                .interact()?;
+            if !proceed {
+                say!("Cancelled");
+                return Ok(());
+            }
+        }
+    }
+
+    if interactive && !cli.yes {
+        eprintln!("About to deploy:");
+        eprintln!("  Repository:  {}", repository);
+        if cli.tag.is_some() {
+            eprintln!("  Tag:         {}", branch_name);
+        } else {
+            eprintln!("  Branch:      {}", branch_name);
+        }
+        if workflow_ref != branch_name {
+            eprintln!("  Workflow on: {}", workflow_ref);
+        }
+        eprintln!("  Commit:      {}", commit_hash);
+        eprintln!("  Environment: {}", selected_envs.join(", "));
+        let proceed = Confirm::with_theme(&ColorfulTheme::default())
+            .with_prompt("Trigger the deploy?")
+            .default(true)
+            .interact()?;
+        if !proceed {
+            say!("Cancelled");
+            return Ok(());
+        }
+    }
+
+    if !cli.no_remember {
+        state.remember_selection(&repository, &selected_envs);
+    }
+
+    // A failed environment does not stop the others; failures are reported at the end
+    let mut failures: Vec<(&str, anyhow::Error)> = Vec::new();
+    let mut triggered: Vec<Triggered> = Vec::new();
+    for env in &selected_envs {
+        // Trigger the GitHub Action using the proper workflow ID
+        let mut deployment_id = None;
+        if cli.create_deployment {
+            let description = format!("{} ({})", branch_name, commit_hash);
+            match deployments::create(&octocrab, &owner, &repo, &deploy_sha, env, &description)
+                .await
+            {
+                Ok(id) => {
+                    say!("Created deployment {} for {}", id, env);
+                    deployment_id = Some(id);
+                }
+                Err(err) => eprintln!("Warning: {}", github::one_line(&err)),
+            }
+        }
+        let dispatched_at = Utc::now();
+        // Runs of earlier environments share the branch and SHA, so skip them
+        let dispatch = runs::Dispatch {
+            owner: &owner,
+            repo: &repo,
+            workflow_id: &workflow_id,
+            branch: &workflow_ref,
+            head_sha: &workflow_head_sha,
+            dispatched_at,
+            claimed: triggered
+                .iter()
+                .filter_map(|earlier| earlier.run.as_ref().map(|run| run.id))
+                .collect(),
+        };
+        // Recorded first, so a deploy cut short still shows in the history
+        state.record(Deployment {
+            repository: repository.clone(),
+            environment: env.clone(),
+            pr_number: selected_pr.as_ref().map(|pr| pr.number),
+            branch: branch_name.clone(),
+            dispatch_ref: (workflow_ref != branch_name).then(|| workflow_ref.clone()),
+            commit_sha: deploy_sha.clone(),
+            deployed_at: dispatched_at,
+            result: Some("dispatching".to_string()),
+            duration_secs: None,
+        });
+        if let Err(err) = state.save() {
+            eprintln!("Warning: failed to record deployment: {:#}", err);
+        }
+        if cli.print_curl {
+            let curl = curl_command(
+                base_url.as_ref(),
+                &owner,
+                &repo,
+                &workflow_id,
+                &dispatch_body(env),
+            );
+            // Keeps stdout to the JSON results
+            if cli.output == OutputFormat::Json {
+                eprintln!("{}", curl);
+            } else {
+                println!("{}", curl);
+            }
+        }
+        let result = dispatch_workflow(&octocrab, &dispatch, &dispatch_body(env)).await;
+        if let Err(err) = result {
+            eprintln!("Failed to deploy to {}: {:#}", env, err);
+            state.set_result(&repository, env, dispatched_at, "failed");
+            if let Some(id) = deployment_id {
+                report_deployment_status(&octocrab, &owner, &repo, id, "error", None).await;
+            }
+            failures.push((env, err));
+            continue;
+        }
+        state.set_result(&repository, env, dispatched_at, "dispatched");
+
+        if let Ok(webhook_url) = env::var("SLACK_WEBHOOK_URL") {
+            let notice = notify::DeployNotice {
+                deployer: &current_user,
+                repository: &repository,
+                environment: env,
+                branch: &branch_name,
+                commit: &commit_hash,
+                pr: selected_pr.as_ref(),
+            };
+            if let Err(err) = notify::slack(&webhook_url, &notice).await {
+                eprintln!("Warning: failed to post the Slack notification: {:#}", err);
+            }
+        }
+
+        let run = {
+            let _spinner = Spinner::start("Looking for the workflow run...");
+            runs::locate_dispatched_run(&octocrab, &dispatch, RUN_LOOKUP_WINDOW).await
+        }
+        .unwrap_or_else(|err| {
+            eprintln!("Warning: could not look up the workflow run: {:#}", err);
+            None
+        });
+        let run_url = match &run {
+            Some(run) => Some(run.html_url.to_string()),
+            None => workflows::workflow_runs_page(&octocrab, &owner, &repo, &workflow_id)
+                .await
+                .ok(),
+        };
+        let mut summary = vec![("Repository", repository.clone())];
+        if let Some(pr) = &selected_pr {
+            summary.push(("PR", format!("#{}", pr.number)));
+        }
+        summary.push(("Branch", branch_name.clone()));
+        summary.push(("Commit", commit_hash.clone()));
+        summary.push(("Environment", env.clone()));
+        match (&run, &run_url) {
+            (Some(_), Some(url)) => summary.push(("Run", url.clone())),
+            (None, Some(url)) => summary.push(("Run", format!("not registered yet; see {}", url))),
+            _ => {}
+        }
+        output::print_summary("Successfully triggered GitHub Action", &summary);
+        if !cli.no_status {
+            let status = checks::CommitStatus {
+                state: "pending",
+                context: format!("deploy/{}", env),
+                description: format!("Deploying to {}", env),
+                target_url: run_url.as_deref(),
+            };
+            report_deploy_status(&octocrab, &owner, &repo, &deploy_sha, &status).await;
+        }
+        if let Some(id) = deployment_id {
+            let log_url = run_url.as_deref();
+            report_deployment_status(&octocrab, &owner, &repo, id, "in_progress", log_url).await;
+        }
+        let mut hook_failed = false;
+        if let Some(hook) = &post_hook {
+            say!("Running the post-deploy hook for {}...", env);
+            let context = hooks::HookContext {
+                repository: &repository,
+                environment: env,
+                commit: &commit_hash,
+                pr: selected_pr.as_ref().map(|pr| pr.number),
+                branch: &branch_name,
+                run_url: run_url.as_deref(),
+            };
+            if let Err(err) = hook.run(&context, cli.quiet).await {
+                if hook.fatal {
+                    failures.push((env, err));
+                    hook_failed = true;
+                } else {
+                    eprintln!("Warning: {:#}", err);
+                }
+            }
+        }
+        triggered.push(Triggered {
+            environment: env,
+            dispatch,
+            run,
+            run_url,
+            deployment_id,
+            result: "dispatched".to_string(),
+            duration_secs: None,
+        });
+        // A fatal hook failure stops the remaining environments too
+        if hook_failed {
+            break;
+        }
+    }
+    if let Err(err) = state.save() {
+        eprintln!("Warning: failed to record deployment: {:#}", err);
+    }
+    if cli.open {
+        if output::can_open_browser() {
+            for url in triggered.iter().filter_map(|t| t.run_url.as_deref()) {
+                if let Err(err) = output::open_in_browser(url) {
+                    eprintln!("Warning: failed to open {}: {}", url, err);
+                }
+            }
+        } else {
+            say!("Not opening the run in a browser, as none is available here");
+        }
+    }
+
+    if cli.wait {
+        // Runs progress in parallel, so one deadline covers all of them
+        let deadline = std::time::Instant::now() + Duration::from_secs(cli.wait_timeout);
+        for triggered in &mut triggered {
+            say!(
+                "Waiting for the workflow run on {} to complete...",
+                triggered.environment
+            );
+            let remaining = deadline.saturating_duration_since(std::time::Instant::now());
+            let outcome = runs::wait_for_run(
+                &octocrab,
+                &triggered.dispatch,
+                triggered.run.take(),
+                remaining,
+                Duration::from_secs(cli.poll_interval),
+            )
+            .await;
+
+            let failure = match outcome {
+                Err(err) => Some(err),
+                Ok(runs::WaitOutcome::Completed(run)) => {
+                    let mut conclusion = run.conclusion.as_deref().unwrap_or("unknown");
+                    // A completed run is last updated when it finishes; runs are
+                    // waited for one after another, so polling would overcount
+                    let duration = (run.updated_at - triggered.dispatch.dispatched_at)
+                        .num_seconds()
+                        .max(0) as u64;
+                    say!(
+                        "Run finished with conclusion: {} after {}",
+                        conclusion,
+                        state::format_duration(duration)
+                    );
+                    // A run that succeeded only counts once the service is up
+                    let mut unhealthy = None;
+                    if let (Some(check), "success") = (&health_check, conclusion) {
+                        let url = check.url(triggered.environment);
+                        let healthy = {
+                            let _spinner = Spinner::start(format!("Checking {}...", url));
+                            health::wait_until_healthy(&url, Duration::from_secs(cli.health_timeout))
+                                .await
+                        };
+                        match healthy {
+                            Ok(()) => say!("{} is healthy", url),
+                            Err(err) => {
+                                conclusion = "unhealthy";
+                                unhealthy = Some(err);
+                            }
+                        }
+                    }
+                    triggered.result = conclusion.to_string();
+                    triggered.run_url = Some(run.html_url.to_string());
+                    triggered.duration_secs = Some(duration);
+                    state.set_result(
+                        &repository,
+                        triggered.environment,
+                        triggered.dispatch.dispatched_at,
+                        conclusion,
+                    );
+                    state.set_duration(
+                        &repository,
+                        triggered.environment,
+                        triggered.dispatch.dispatched_at,
+                        duration,
+                    );
+                    if !cli.no_status {
+                        let env = triggered.environment;
+                        let (state, description) = match conclusion {
+                            "success" => ("success", format!("Deployed to {}", env)),
+                            _ => ("failure", format!("Deploy to {} ended with {}", env, conclusion)),
+                        };
+                        let status = checks::CommitStatus {
+                            state,
+                            context: format!("deploy/{}", env),
+                            description,
+                            target_url: Some(run.html_url.as_str()),
+                        };
+                        report_deploy_status(&octocrab, &owner, &repo, &deploy_sha, &status).await;
+                    }
+                    if let Some(id) = triggered.deployment_id {
+                        let state = if conclusion == "success" { "success" } else { "failure" };
+                        let log_url = Some(run.html_url.as_str());
+                        report_deployment_status(&octocrab, &owner, &repo, id, state, log_url)
+                            .await;
+                    }
+                    unhealthy.or_else(|| {
+                        (conclusion != "success").then(|| {
+                            anyhow::anyhow!("Workflow run concluded with '{}'", conclusion)
+                        })
+                    })
+                }
+                Ok(runs::WaitOutcome::TimedOut(Some(run))) => Some(exit::fail(
+                    Failure::WaitTimeout,
+                    format!(
+                        "Timed out after {}s waiting for the run to complete (last status: {}). Run: {}",
+                        cli.wait_timeout, run.status, run.html_url
+                    ),
+                )),
+                Ok(runs::WaitOutcome::TimedOut(None)) => Some(exit::fail(
+                    Failure::WaitTimeout,
+                    format!(
+                        "Timed out after {}s without finding the triggered workflow run{}",
+                        cli.wait_timeout,
+                        triggered
+                            .run_url
+                            .as_ref()
+                            .map(|url| format!("; see {}", url))
+                            .unwrap_or_default()
+                    ),
+                )),
+            };
+            if let Some(err) = &failure {
+                if Failure::classify(err) == Some(Failure::WaitTimeout) {
+                    triggered.result = "timed_out".to_string();
+                }
+            }
+            if cli.notify {
+                let outcome = match (&failure, triggered.result.as_str()) {
+                    (None, _) => "succeeded".to_string(),
+                    (Some(_), "timed_out") => "timed out".to_string(),
+                    (Some(_), "dispatched") => "could not be followed".to_string(),
+                    (Some(_), conclusion) => format!("ended with {}", conclusion),
+                };
+                output::notify_desktop(
+                    &format!("Deploy to {} {}", triggered.environment, outcome),
+                    &format!("{} ({}) in {}", branch_name, commit_hash, repository),
+                );
+            }
+            if let Some(err) = failure {
+                failures.push((triggered.environment, err));
+            }
+        }
+        if let Err(err) = state.save() {
+            eprintln!("Warning: failed to record the run results: {:#}", err);
+        }
+    }
+
+    let results: Vec<DeployResult> = triggered
+        .iter()
+        .map(|triggered| DeployResult {
+            branch: &branch_name,
+            commit: &commit_hash,
+            environment: triggered.environment,
+            workflow_id: &workflow_id,
+            pr_number: selected_pr.as_ref().map(|pr| pr.number),
+            dispatched_at: triggered.dispatch.dispatched_at,
+            run_url: triggered.run_url.as_deref(),
+            deployment_id: triggered.deployment_id,
+            duration_secs: triggered.duration_secs,
+        })
+        .collect();
+    if let Some(url) = &cli.callback_url {
+        let secret = env::var("DEPLOY_CALLBACK_SECRET").ok();
+        for (triggered, deploy) in triggered.iter().zip(&results) {
+            let payload = CallbackPayload {
+                repository: &repository,
+                deploy,
+                result: &triggered.result,
+            };
+            if let Err(err) = notify::callback(url.as_str(), secret.as_deref(), &payload).await {
+                eprintln!(
+                    "Warning: failed to report the deploy to {}: {:#}",
+                    triggered.environment, err
+                );
+            }
+        }
+    }
+
+    if cli.output == OutputFormat::Json {
+        for result in &results {
+            println!("{}", serde_json::to_string(result)?);
+        }
+    } else if cli.quiet {
+        for triggered in &triggered {
+            println!(
+                "Deployed {} ({}) to {}{}",
+                branch_name,
+                commit_hash,
+                triggered.environment,
+                triggered
+                    .run_url
+                    .as_deref()
+                    .map(|url| format!(": {}", url))
+                    .unwrap_or_default()
+            );
+        }
+    }
+
+    if selected_envs.len() == 1 {
+        if let Some((env, err)) = failures.pop() {
+            return Err(err.context(format!("Deploy to {} failed", env)));
+        }
+    }
+    if !failures.is_empty() {
+        let lines: Vec<String> = failures
+            .iter()
+            .map(|(env, err)| format!("{}: {:#}", env, err))
+            .collect();
+        let message = format!(
+            "{} of {} deploys failed:\n  {}",
+            failures.len(),
+            selected_envs.len(),
+            lines.join("\n  ")
+        );
+        // Keep a specific exit code when every failure has the same cause
+        let first = Failure::classify(&failures[0].1);
+        let shared = failures
+            .iter()
+            .all(|(_, err)| Failure::classify(err) == first);
+        return Err(match first.filter(|_| shared) {
+            Some(failure) => exit::fail(failure, message),
+            None => anyhow::anyhow!(message),
+        });
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use wiremock::matchers::{body_json, method, path, query_param};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    /// A client sending every request to `server`, as `GITHUB_BASE_URL` would.
+    fn client(server: &MockServer) -> Arc<Octocrab> {
+        let base_url = Url::parse(&server.uri()).unwrap();
+        Arc::new(github_client(Some("test-token".to_string()), Some(&base_url)).unwrap())
+    }
+
+    fn search(limit: usize, per_page: usize) -> PrSearch {
+        PrSearch {
+            owner: "org".to_string(),
+            repo: "app".to_string(),
+            author: Some("alice".to_string()),
+            labels: Vec::new(),
+            updated_since: None,
+            base: None,
+            limit,
+            per_page,
+        }
+    }
+
+    fn issue(number: u64) -> serde_json::Value {
+        json!({ "number": number, "pull_request": {}, "labels": [] })
+    }
+
+    fn labeled(number: u64) -> serde_json::Value {
+        json!({ "number": number, "pull_request": {}, "labels": [{ "name": "deploy" }] })
+    }
+
+    fn pull(number: u64) -> serde_json::Value {
+        json!({
+            "url": format!("https://api.github.com/repos/org/app/pulls/{}", number),
+            "id": number,
+            "number": number,
+            "head": { "ref": format!("feature-{}", number), "sha": "a".repeat(40) },
+            "base": { "ref": "main", "sha": "b".repeat(40) },
+        })
+    }
+
+    async fn mount_pulls(server: &MockServer, numbers: impl IntoIterator<Item = u64>) {
+        for number in numbers {
+            Mock::given(method("GET"))
+                .and(path(format!("/repos/org/app/pulls/{}", number)))
+                .respond_with(ResponseTemplate::new(200).set_body_json(pull(number)))
+                .mount(server)
+                .await;
+        }
+    }
+
+    async fn mount_search_page(
+        server: &MockServer,
+        page: u64,
+        total_count: usize,
+        items: Vec<serde_json::Value>,
+    ) {
+        Mock::given(method("GET"))
+            .and(path("/search/issues"))
+            .and(query_param("page", page.to_string()))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .set_body_json(json!({ "total_count": total_count, "items": items })),
+            )
+            .expect(1)
+            .mount(server)
+            .await;
+    }
+
+    fn numbers(list: &PrList) -> Vec<u64> {
+        list.prs.iter().map(|pr| pr.number).collect()
+    }
+
+    #[test]
+    fn query_lists_every_qualifier() {
+        let mut search = search(10, 100);
+        assert_eq!(
+            search.query(),
+            "type:pr state:open repo:org/app author:alice"
+        );
+        search.author = None;
+        search.updated_since = NaiveDate::from_ymd_opt(2024, 3, 1);
+        search.base = Some("release".to_string());
+        assert_eq!(
+            search.query(),
+            "type:pr state:open repo:org/app updated:>=2024-03-01 base:release"
+        );
+    }
+
+    #[tokio::test]
+    async fn fetch_prs_sends_the_search_query() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/search/issues"))
+            .and(query_param(
+                "q",
+                "type:pr state:open repo:org/app author:alice",
+            ))
+            .and(query_param("sort", "updated"))
+            .and(query_param("order", "desc"))
+            .and(query_param("per_page", "30"))
+            .and(query_param("page", "1"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .set_body_json(json!({ "total_count": 1, "items": [issue(7)] })),
+            )
+            .expect(1)
+            .mount(&server)
+            .await;
+        mount_pulls(&server, [7]).await;
+
+        let list = fetch_prs(client(&server), search(10, 30)).await.unwrap();
+        assert_eq!(numbers(&list), [7]);
+        assert!(!list.truncated);
+    }
+
+    #[tokio::test]
+    async fn fetch_prs_follows_the_pages() {
+        let server = MockServer::start().await;
+        mount_search_page(&server, 1, 3, vec![issue(1), issue(2)]).await;
+        mount_search_page(&server, 2, 3, vec![issue(3)]).await;
+        mount_pulls(&server, 1..=3).await;
+
+        let list = fetch_prs(client(&server), search(10, 2)).await.unwrap();
+        assert_eq!(numbers(&list), [1, 2, 3]);
+        assert!(!list.truncated);
+    }
+
+    #[tokio::test]
+    async fn fetch_prs_matches_labels_across_pages() {
+        let server = MockServer::start().await;
+        mount_search_page(&server, 1, 4, vec![labeled(1), issue(2)]).await;
+        mount_search_page(&server, 2, 4, vec![issue(3), labeled(4)]).await;
+        mount_pulls(&server, [1, 4]).await;
+
+        let mut search = search(1, 2);
+        search.labels = vec!["deploy".to_string()];
+        let list = fetch_prs(client(&server), search).await.unwrap();
+        assert_eq!(numbers(&list), [1]);
+        assert!(list.truncated);
+    }
+
+    #[tokio::test]
+    async fn fetch_prs_stops_paging_at_the_result_cap() {
+        let server = MockServer::start().await;
+        let per_page = 100;
+        for page in 1..=(MAX_SEARCH_RESULTS / per_page) as u64 {
+            let first = (page - 1) * per_page as u64 + 1;
+            let items = (first..first + per_page as u64).map(labeled).collect();
+            mount_search_page(&server, page, 2000, items).await;
+        }
+        Mock::given(method("GET"))
+            .and(path("/search/issues"))
+            .and(query_param(
+                "page",
+                ((MAX_SEARCH_RESULTS / per_page) + 1).to_string(),
+            ))
+            .respond_with(ResponseTemplate::new(500))
+            .expect(0)
+            .mount(&server)
+            .await;
+        mount_pulls(&server, 1..=3).await;
+
+        // A label keeps the limit from ending the search before the cap does
+        let mut search = search(3, per_page);
+        search.labels = vec!["deploy".to_string()];
+        let list = fetch_prs(client(&server), search).await.unwrap();
+        assert_eq!(numbers(&list), [1, 2, 3]);
+        assert!(list.truncated);
+    }
+
+    #[tokio::test]
+    async fn fetch_prs_keeps_the_search_order() {
+        let server = MockServer::start().await;
+        mount_search_page(&server, 1, 4, (1..=4).map(issue).collect()).await;
+        // The first PRs answer last
+        for number in 1..=4u64 {
+            Mock::given(method("GET"))
+                .and(path(format!("/repos/org/app/pulls/{}", number)))
+                .respond_with(
+                    ResponseTemplate::new(200)
+                        .set_body_json(pull(number))
+                        .set_delay(Duration::from_millis(200 - 50 * number)),
+                )
+                .mount(&server)
+                .await;
+        }
+
+        let list = fetch_prs(client(&server), search(10, 100)).await.unwrap();
+        assert_eq!(numbers(&list), [1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn branch_tip_without_commits_falls_back_to_the_pr_head() {
+        let pr: PullRequest = serde_json::from_value(pull(5)).unwrap();
+        let sha = branch_tip(&[], Some(&pr), "feature-5", "org", "app").unwrap();
+        assert_eq!(sha, "a".repeat(40));
+
+        let err = branch_tip(&[], None, "feature-5", "org", "app").unwrap_err();
+        assert!(err
+            .to_string()
+            .starts_with("No commits found on branch 'feature-5' in org/app"));
+    }
+
+    #[test]
+    fn select_commit_without_commits_fails_before_prompting() {
+        let filter = CommitFilter {
+            author: Some("alice".to_string()),
+            message: None,
+        };
+        let err = select_commit(&[], &filter, 10).unwrap_err();
+        assert!(err.to_string().starts_with("None of the last 0 commits"));
+    }
+
+    #[test]
+    fn requested_pr_missing_from_an_empty_list_exits_with_no_pr() {
+        let search = search(10, 100);
+        assert_eq!(no_prs_message(&[], Some(7), &search), None);
+        let err =
+            select_pr(Vec::new(), Some(7), None, &search, &HashMap::new(), false).unwrap_err();
+        assert_eq!(Failure::classify(&err), Some(Failure::NoPr));
+        assert_eq!(Failure::NoPr.code(), 3);
+    }
+
+    #[test]
+    fn empty_list_without_a_requested_pr_ends_before_the_prompt() {
+        let mut search = search(10, 100);
+        assert_eq!(
+            no_prs_message(&[], None, &search).as_deref(),
+            Some("No open PRs found for alice in org/app")
+        );
+        search.labels = vec!["deploy".to_string()];
+        assert_eq!(
+            no_prs_message(&[], None, &search).as_deref(),
+            Some("None of the alice's open PRs in org/app carry the label(s): deploy")
+        );
+        let pr: PullRequest = serde_json::from_value(pull(5)).unwrap();
+        assert_eq!(no_prs_message(&[pr], None, &search), None);
+    }
+
+    #[test]
+    fn short_sha_keeps_short_input_whole() {
+        assert_eq!(short_sha("0123456789abcdef"), "0123456");
+        assert_eq!(short_sha("abc"), "abc");
+        // Cutting inside a multibyte character falls back to the whole input
+        assert_eq!(short_sha("ééééé"), "ééééé");
+    }
+
+    #[test]
+    fn validate_sha_rejects_what_is_not_a_sha() {
+        assert!(validate_sha("0123456").is_ok());
+        assert!(validate_sha(&"a".repeat(40)).is_ok());
+        assert!(validate_sha("012345").is_err());
+        assert!(validate_sha("0123456g").is_err());
+        assert!(validate_sha("main").is_err());
+        assert!(validate_sha("0123456é").is_err());
+        assert!(validate_sha("ééééééé").is_err());
+    }
+
+    #[test]
+    fn commit_input_shortens_unless_full_sha() {
+        let sha = "0123456789abcdef0123456789abcdef01234567";
+        assert_eq!(commit_input(sha, false).unwrap(), "0123456");
+        assert_eq!(commit_input(sha, true).unwrap(), sha);
+        assert!(commit_input("xyz", true).is_err());
+    }
+
+    /// Writes `contents` to a file of its own in the temporary directory.
+    fn temp_file(name: &str, contents: &str) -> PathBuf {
+        let path = env::temp_dir().join(format!("deploy-test-{}-{}", std::process::id(), name));
+        std::fs::write(&path, contents).unwrap();
+        path
+    }
+
+    #[test]
+    fn token_sources_are_tried_in_order() {
+        let token_file = temp_file("token", "from-file\n");
+        let env_token_file = temp_file("env-token", "from-env-file");
+        let gh_hosts = temp_file("hosts.yml", "github.com:\n  oauth_token: from-gh\n");
+        let sources = || TokenSources {
+            stdin: Some("from-stdin"),
+            env_token: Some("from-env".to_string()),
+            token_file: Some(&token_file),
+            env_token_file: Some(env_token_file.clone()),
+            gh_hosts: Some(gh_hosts.clone()),
+        };
+        let found =
+            |sources: TokenSources| sources.find("github.com").unwrap().map(|(token, _)| token);
+
+        assert_eq!(found(sources()).as_deref(), Some("from-stdin"));
+        let sources = || TokenSources {
+            stdin: None,
+            ..sources()
+        };
+        assert_eq!(found(sources()).as_deref(), Some("from-env"));
+        let sources = || TokenSources {
+            env_token: None,
+            ..sources()
+        };
+        assert_eq!(found(sources()).as_deref(), Some("from-file"));
+        let sources = || TokenSources {
+            token_file: None,
+            ..sources()
+        };
+        assert_eq!(found(sources()).as_deref(), Some("from-env-file"));
+        let sources = || TokenSources {
+            env_token_file: None,
+            ..sources()
+        };
+        assert_eq!(found(sources()).as_deref(), Some("from-gh"));
+        assert_eq!(sources().find("github.example.com").unwrap(), None);
+
+        for path in [token_file, env_token_file, gh_hosts] {
+            std::fs::remove_file(path).unwrap();
+        }
+    }
+
+    #[test]
+    fn missing_token_is_an_auth_error_naming_the_host() {
+        let err = missing_token_error("github.example.com");
+        assert_eq!(Failure::classify(&err), Some(Failure::Auth));
+        assert!(err
+            .to_string()
+            .contains("gh CLI config for github.example.com"));
+    }
+
+    #[test]
+    fn unreadable_token_file_is_an_auth_error() {
+        let missing = env::temp_dir().join(format!("deploy-test-{}-missing", std::process::id()));
+        let sources = TokenSources {
+            stdin: None,
+            env_token: None,
+            token_file: Some(&missing),
+            env_token_file: None,
+            gh_hosts: None,
+        };
+        let err = sources.find("github.com").unwrap_err();
+        assert_eq!(Failure::classify(&err), Some(Failure::Auth));
+    }
+
+    fn reference(name: &str) -> serde_json::Value {
+        json!({
+            "ref": name,
+            "node_id": "REF",
+            "url": "https://api.github.com/repos/org/app/git/ref",
+            "object": {
+                "type": "commit",
+                "sha": "c".repeat(40),
+                "url": "https://api.github.com/repos/org/app/git/commits/c",
+            },
+        })
+    }
+
+    #[tokio::test]
+    async fn branch_and_tag_names_are_encoded_in_routes() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/repos/org/app/git/ref/heads/feat/a%23b"))
+            .respond_with(
+                ResponseTemplate::new(200).set_body_json(reference("refs/heads/feat/a#b")),
+            )
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/repos/org/app/git/ref/tags/v1%2Brc%231"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(reference("refs/tags/v1+rc#1")))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let octocrab = client(&server);
+        check_branch_exists(&octocrab, "org", "app", "feat/a#b")
+            .await
+            .unwrap();
+        let sha = resolve_tag(&octocrab, "org", "app", "v1+rc#1")
+            .await
+            .unwrap();
+        assert_eq!(sha, "c".repeat(40));
+    }
+
+    #[test]
+    fn parse_repository_needs_owner_and_name() {
+        assert_eq!(
+            parse_repository("octocat/hello.world").unwrap(),
+            ("octocat".to_string(), "hello.world".to_string())
+        );
+        assert!(parse_repository("octocat").is_err());
+        assert!(parse_repository("octocat/").is_err());
+        assert!(parse_repository("octo/cat/hello").is_err());
+    }
+
+    #[test]
+    fn redeploys_need_a_token_unless_dry_run() {
+        let read_only = |args: &[&str]| Cli::parse_from(args).is_read_only();
+        assert!(!read_only(&[
+            "deploy",
+            "rollback",
+            "--env",
+            "experimental1"
+        ]));
+        assert!(!read_only(&["deploy", "again"]));
+        assert!(read_only(&[
+            "deploy",
+            "--dry-run",
+            "rollback",
+            "--env",
+            "experimental1"
+        ]));
+        assert!(read_only(&["deploy", "--dry-run", "again"]));
+    }
+
+    #[test]
+    fn per_page_defaults_to_githubs_page_size() {
+        assert_eq!(Cli::parse_from(["deploy"]).per_page, 30);
+        assert_eq!(
+            Cli::parse_from(["deploy", "--per-page", "100"]).per_page,
+            100
+        );
+        assert!(Cli::try_parse_from(["deploy", "--per-page", "0"]).is_err());
+        assert!(Cli::try_parse_from(["deploy", "--per-page", "101"]).is_err());
+    }
+
+    #[tokio::test]
+    async fn dispatch_workflow_posts_the_body() {
+        let server = MockServer::start().await;
+        let body = json!({
+            "ref": "feature-1",
+            "inputs": { "environment": "experimental3", "sha": "a".repeat(40) },
+        });
+        Mock::given(method("POST"))
+            .and(path(
+                "/repos/org/app/actions/workflows/deploy.yml/dispatches",
+            ))
+            .and(body_json(&body))
+            .respond_with(ResponseTemplate::new(204))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let head_sha = "a".repeat(40);
+        let dispatch = runs::Dispatch {
+            owner: "org",
+            repo: "app",
+            workflow_id: "deploy.yml",
+            branch: "feature-1",
+            head_sha: &head_sha,
+            dispatched_at: Utc::now(),
+            claimed: Vec::new(),
+        };
+        dispatch_workflow(&client(&server), &dispatch, &body)
+            .await
+            .unwrap();
+    }
+}
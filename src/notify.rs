@@ -0,0 +1,104 @@
+use crate::github;
+use anyhow::{Context, Result};
+use octocrab::models::pulls::PullRequest;
+use serde::Serialize;
+
+/// Header carrying the callback body's HMAC-SHA256, like GitHub's own
+/// `X-Hub-Signature-256`.
+const SIGNATURE_HEADER: &str = "X-Deploy-Signature-256";
+
+/// What a deploy notification reports.
+pub struct DeployNotice<'a> {
+    pub deployer: &'a str,
+    pub repository: &'a str,
+    pub environment: &'a str,
+    pub branch: &'a str,
+    pub commit: &'a str,
+    pub pr: Option<&'a PullRequest>,
+}
+
+impl DeployNotice<'_> {
+    /// Renders the notice in Slack's mrkdwn, e.g.
+    /// `*alice* deployed <url|PR #12: Fix login> (`abc1234`) to *experimental3* in org/repo`.
+    fn to_slack_text(&self) -> String {
+        let what = match self.pr {
+            Some(pr) => {
+                let label = format!(
+                    "PR #{}: {}",
+                    pr.number,
+                    pr.title.as_deref().unwrap_or_default()
+                );
+                match &pr.html_url {
+                    Some(url) => format!("<{}|{}>", url, escape(&label)),
+                    None => escape(&label),
+                }
+            }
+            None => format!("branch `{}`", escape(self.branch)),
+        };
+        format!(
+            "*{}* deployed {} (`{}`) to *{}* in {}",
+            escape(self.deployer),
+            what,
+            self.commit,
+            escape(self.environment),
+            escape(self.repository)
+        )
+    }
+}
+
+/// Escapes the characters Slack treats as control sequences.
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+/// Posts `notice` to a Slack incoming webhook. Errors leave out the URL,
+/// which is itself the credential.
+pub async fn slack(webhook_url: &str, notice: &DeployNotice<'_>) -> Result<()> {
+    let client = reqwest::Client::builder()
+        .timeout(github::http_timeout())
+        .build()?;
+    client
+        .post(webhook_url)
+        .json(&serde_json::json!({ "text": notice.to_slack_text() }))
+        .send()
+        .await
+        .map_err(reqwest::Error::without_url)
+        .context("Failed to reach the Slack webhook")?
+        .error_for_status()
+        .map_err(reqwest::Error::without_url)
+        .context("The Slack webhook rejected the message")?;
+    Ok(())
+}
+
+/// POSTs `payload` as JSON to `url`. With a `secret`, the body is signed with
+/// HMAC-SHA256 and the signature sent as `sha256=<hex>` in
+/// `X-Deploy-Signature-256`, so the receiver can check it came from us.
+pub async fn callback(url: &str, secret: Option<&str>, payload: &impl Serialize) -> Result<()> {
+    let body = serde_json::to_vec(payload)?;
+    let client = reqwest::Client::builder()
+        .timeout(github::http_timeout())
+        .build()?;
+    let mut request = client
+        .post(url)
+        .header(reqwest::header::CONTENT_TYPE, "application/json");
+    if let Some(secret) = secret {
+        let key = ring::hmac::Key::new(ring::hmac::HMAC_SHA256, secret.as_bytes());
+        let signature = ring::hmac::sign(&key, &body);
+        let hex: String = signature
+            .as_ref()
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect();
+        request = request.header(SIGNATURE_HEADER, format!("sha256={}", hex));
+    }
+    request
+        .body(body)
+        .send()
+        .await
+        .context("Failed to reach the callback URL")?
+        .error_for_status()
+        .context("The callback URL rejected the deploy result")?;
+    Ok(())
+}
@@ -0,0 +1,246 @@
+use dialoguer::console::{self, style};
+use std::io::{IsTerminal, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc;
+use std::thread::JoinHandle;
+use std::time::Duration;
+use tracing::level_filters::LevelFilter;
+use tracing_subscriber::filter::Targets;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+
+static QUIET: AtomicBool = AtomicBool::new(false);
+
+/// Suppresses informational output, e.g. while stdout carries JSON.
+pub fn set_quiet(quiet: bool) {
+    QUIET.store(quiet, Ordering::Relaxed);
+}
+
+pub fn is_quiet() -> bool {
+    QUIET.load(Ordering::Relaxed)
+}
+
+/// Sends this crate's debug logs to stderr. Dependencies stay silent, since
+/// their request spans include full URLs.
+pub fn init_logging() {
+    let filter = Targets::new().with_target(env!("CARGO_CRATE_NAME"), LevelFilter::DEBUG);
+    tracing_subscriber::registry()
+        .with(
+            tracing_subscriber::fmt::layer()
+                .with_writer(std::io::stderr)
+                .with_target(false),
+        )
+        .with(filter)
+        .init();
+}
+
+/// Prints an informational line to stdout unless output is suppressed.
+macro_rules! say {
+    ($($arg:tt)*) => {
+        if !$crate::output::is_quiet() {
+            println!($($arg)*);
+        }
+    };
+}
+
+pub(crate) use say;
+
+const SPINNER_FRAMES: &[char] = &['⠋', '⠙', '⠹', '⠸', '⠼', '⠴', '⠦', '⠧', '⠇', '⠏'];
+const SPINNER_INTERVAL: Duration = Duration::from_millis(80);
+
+/// Animates a message on stdout until dropped, then clears it. When stdout is
+/// not a terminal the message is printed once instead, like `say!`.
+pub struct Spinner {
+    stop: Option<mpsc::Sender<()>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Spinner {
+    pub fn start(message: impl Into<String>) -> Spinner {
+        let message = message.into();
+        if is_quiet() {
+            return Spinner {
+                stop: None,
+                thread: None,
+            };
+        }
+        if !std::io::stdout().is_terminal() {
+            println!("{}", message);
+            return Spinner {
+                stop: None,
+                thread: None,
+            };
+        }
+
+        let (stop, stopped) = mpsc::channel::<()>();
+        let thread = std::thread::spawn(move || {
+            let mut stdout = std::io::stdout();
+            for frame in SPINNER_FRAMES.iter().cycle() {
+                let _ = write!(stdout, "\r{} {}", frame, message);
+                let _ = stdout.flush();
+                // Both a message and the sender being dropped mean stop
+                if !matches!(
+                    stopped.recv_timeout(SPINNER_INTERVAL),
+                    Err(mpsc::RecvTimeoutError::Timeout)
+                ) {
+                    break;
+                }
+            }
+            let _ = write!(stdout, "\r\x1b[2K");
+            let _ = stdout.flush();
+        });
+        Spinner {
+            stop: Some(stop),
+            thread: Some(thread),
+        }
+    }
+}
+
+impl Drop for Spinner {
+    fn drop(&mut self) {
+        drop(self.stop.take());
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Prints `rows` under `headers` with the columns aligned.
+pub fn print_table<const N: usize>(headers: &[&str; N], rows: &[[String; N]]) {
+    let mut widths = headers.map(str::len);
+    for row in rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+
+    let render = |cells: Vec<&str>| {
+        let line: Vec<String> = cells
+            .iter()
+            .zip(widths)
+            .map(|(cell, width)| format!("{:<width$}", cell, width = width))
+            .collect();
+        println!("{}", line.join("  ").trim_end());
+    };
+    render(headers.to_vec());
+    for row in rows {
+        render(row.iter().map(String::as_str).collect());
+    }
+}
+
+/// Whether a browser can be opened, i.e. the tool runs in a terminal on a
+/// desktop rather than in CI or over SSH.
+pub fn can_open_browser() -> bool {
+    if !std::io::stdout().is_terminal() || std::env::var_os("CI").is_some() {
+        return false;
+    }
+    if cfg!(any(target_os = "macos", windows)) {
+        return true;
+    }
+    std::env::var_os("DISPLAY").is_some() || std::env::var_os("WAYLAND_DISPLAY").is_some()
+}
+
+/// Opens `url` in the default browser.
+pub fn open_in_browser(url: &str) -> std::io::Result<()> {
+    let mut command = if cfg!(target_os = "macos") {
+        std::process::Command::new("open")
+    } else if cfg!(windows) {
+        let mut command = std::process::Command::new("cmd");
+        // The empty argument is the window title `start` would take the URL for
+        command.args(["/C", "start", ""]);
+        command
+    } else {
+        std::process::Command::new("xdg-open")
+    };
+    let status = command
+        .arg(url)
+        .stdin(std::process::Stdio::null())
+        .stdout(std::process::Stdio::null())
+        .stderr(std::process::Stdio::null())
+        .status()?;
+    if !status.success() {
+        return Err(std::io::Error::other(format!(
+            "the browser launcher exited with {}",
+            status
+        )));
+    }
+    Ok(())
+}
+
+/// Shows a desktop notification through the platform's own tool, doing
+/// nothing where there is no desktop or the tool is missing.
+pub fn notify_desktop(title: &str, body: &str) {
+    let mut command = if cfg!(target_os = "macos") {
+        let quote = |text: &str| format!("\"{}\"", text.replace('\\', "\\\\").replace('"', "\\\""));
+        let mut command = std::process::Command::new("osascript");
+        command.args([
+            "-e",
+            &format!(
+                "display notification {} with title {}",
+                quote(body),
+                quote(title)
+            ),
+        ]);
+        command
+    } else if cfg!(windows)
+        || (std::env::var_os("DISPLAY").is_none() && std::env::var_os("WAYLAND_DISPLAY").is_none())
+    {
+        // Windows ships no command for it
+        return;
+    } else {
+        let mut command = std::process::Command::new("notify-send");
+        command.args([title, body]);
+        command
+    };
+    let _ = command
+        .stdin(std::process::Stdio::null())
+        .stdout(std::process::Stdio::null())
+        .stderr(std::process::Stdio::null())
+        .status();
+}
+
+/// Prints labelled `rows` under `title`, boxed and colored when stdout is a
+/// terminal that allows colors (see `NO_COLOR`), and as plain `Label: value`
+/// lines otherwise. Nothing is printed when output is suppressed.
+pub fn print_summary(title: &str, rows: &[(&str, String)]) {
+    if is_quiet() {
+        return;
+    }
+    if !console::colors_enabled() {
+        println!("{}:", title);
+        for (label, value) in rows {
+            println!("{}: {}", label, value);
+        }
+        return;
+    }
+
+    let label_width = rows.iter().map(|(label, _)| label.len()).max().unwrap_or(0);
+    let lines: Vec<String> = rows
+        .iter()
+        .map(|(label, value)| {
+            format!(
+                "{}  {}",
+                style(format!("{:<width$}", label, width = label_width)).dim(),
+                style(value).bold()
+            )
+        })
+        .collect();
+    let title_width = console::measure_text_width(title);
+    let width = lines
+        .iter()
+        .map(|line| console::measure_text_width(line))
+        .max()
+        .unwrap_or(0)
+        .max(title_width + 1);
+
+    println!(
+        "╭─ {} {}╮",
+        style(title).green().bold(),
+        "─".repeat(width - title_width - 1)
+    );
+    for line in &lines {
+        let padding = width - console::measure_text_width(line);
+        println!("│ {}{} │", line, " ".repeat(padding));
+    }
+    println!("╰{}╯", "─".repeat(width + 2));
+}
@@ -0,0 +1,134 @@
+use crate::github;
+use anyhow::{Context, Result};
+use chrono::{DateTime, Local, Utc};
+use octocrab::Octocrab;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// Label of the issue that holds the reservations, so every teammate's copy
+/// of the tool finds the same one.
+const LABEL: &str = "deploy-reservations";
+const TITLE: &str = "Deploy environment reservations";
+const INTRO: &str = "Environments reserved with `deploy reserve`. The tool rewrites the block below; edit it by hand only to clear a reservation.";
+
+/// A claim on an environment until `until`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Reservation {
+    pub owner: String,
+    pub until: DateTime<Utc>,
+}
+
+impl Reservation {
+    /// E.g. `reserved by alice until 14:30`.
+    pub fn describe(&self) -> String {
+        let until = self.until.with_timezone(&Local);
+        let format = if until.date_naive() == Local::now().date_naive() {
+            "%H:%M"
+        } else {
+            "%Y-%m-%d %H:%M"
+        };
+        format!("reserved by {} until {}", self.owner, until.format(format))
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct Issue {
+    number: u64,
+    body: Option<String>,
+}
+
+/// The reservations of a repository's environments, kept as JSON in the body
+/// of an issue labelled `deploy-reservations` so they are shared by everyone
+/// who deploys, not just this machine.
+#[derive(Debug, Default)]
+pub struct Reservations {
+    issue: Option<u64>,
+    /// Unexpired reservations by environment.
+    by_environment: BTreeMap<String, Reservation>,
+}
+
+impl Reservations {
+    /// Reads the reservations, leaving out expired ones. Empty when no issue
+    /// holds any yet.
+    pub async fn load(octocrab: &Octocrab, owner: &str, repo: &str) -> Result<Reservations> {
+        let issues: Vec<Issue> = github::get(
+            octocrab,
+            &format!("/repos/{}/{}/issues", owner, repo),
+            Some(&serde_json::json!({ "labels": LABEL, "state": "open", "per_page": 1 })),
+        )
+        .await
+        .context("Failed to read environment reservations")?;
+        let Some(issue) = issues.into_iter().next() else {
+            return Ok(Reservations::default());
+        };
+        let mut by_environment: BTreeMap<String, Reservation> = issue
+            .body
+            .as_deref()
+            .and_then(json_block)
+            .and_then(|json| serde_json::from_str(json).ok())
+            .unwrap_or_default();
+        let now = Utc::now();
+        by_environment.retain(|_, reservation| reservation.until > now);
+        Ok(Reservations {
+            issue: Some(issue.number),
+            by_environment,
+        })
+    }
+
+    /// Writes the reservations back, creating the issue the first time.
+    pub async fn save(&mut self, octocrab: &Octocrab, owner: &str, repo: &str) -> Result<()> {
+        let body = format!(
+            "{}\n\n```json\n{}\n```\n",
+            INTRO,
+            serde_json::to_string_pretty(&self.by_environment)?
+        );
+        match self.issue {
+            Some(number) => {
+                github::patch::<serde_json::Value, _>(
+                    octocrab,
+                    &format!("/repos/{}/{}/issues/{}", owner, repo, number),
+                    &serde_json::json!({ "body": body }),
+                )
+                .await
+                .with_context(|| format!("Failed to update reservations in issue #{}", number))?;
+            }
+            None => {
+                let created: Issue = github::post(
+                    octocrab,
+                    &format!("/repos/{}/{}/issues", owner, repo),
+                    &serde_json::json!({ "title": TITLE, "body": body, "labels": [LABEL] }),
+                )
+                .await
+                .context("Failed to create the reservations issue")?;
+                self.issue = Some(created.number);
+            }
+        }
+        Ok(())
+    }
+
+    pub fn get(&self, environment: &str) -> Option<&Reservation> {
+        self.by_environment.get(environment)
+    }
+
+    /// The reservation of `environment` by someone other than `login`.
+    pub fn held_by_other(&self, environment: &str, login: &str) -> Option<&Reservation> {
+        self.get(environment)
+            .filter(|reservation| reservation.owner != login)
+    }
+
+    pub fn reserve(&mut self, environment: &str, reservation: Reservation) {
+        self.by_environment
+            .insert(environment.to_string(), reservation);
+    }
+
+    pub fn release(&mut self, environment: &str) -> Option<Reservation> {
+        self.by_environment.remove(environment)
+    }
+}
+
+/// The contents of the first ```json fenced block in `body`.
+fn json_block(body: &str) -> Option<&str> {
+    let start = body.find("```json")? + "```json".len();
+    let end = body[start..].find("```")?;
+    Some(body[start..start + end].trim())
+}
@@ -0,0 +1,80 @@
+use crate::github;
+use anyhow::{Context, Result};
+use octocrab::Octocrab;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+
+#[derive(Debug, Deserialize)]
+struct Review {
+    user: Option<Reviewer>,
+    state: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct Reviewer {
+    login: String,
+}
+
+/// Where the reviews of a PR leave it, counting each reviewer's latest
+/// approval or change request.
+#[derive(Debug, Default)]
+pub struct ReviewState {
+    pub approved_by: Vec<String>,
+    pub changes_requested_by: Vec<String>,
+}
+
+impl ReviewState {
+    /// Why the PR may not be deployed under `--require-approval`, if it may not.
+    pub fn refusal(&self) -> Option<String> {
+        if !self.changes_requested_by.is_empty() {
+            Some(format!(
+                "changes were requested by {}",
+                self.changes_requested_by.join(", ")
+            ))
+        } else if self.approved_by.is_empty() {
+            Some("it has no approving review".to_string())
+        } else {
+            None
+        }
+    }
+}
+
+/// Fetches the reviews of PR `number`. Comments do not change a reviewer's
+/// verdict, while a dismissed review withdraws it, as on GitHub.
+pub async fn review_state(
+    octocrab: &Octocrab,
+    owner: &str,
+    repo: &str,
+    number: u64,
+) -> Result<ReviewState> {
+    let route = format!("/repos/{}/{}/pulls/{}/reviews", owner, repo, number);
+    let reviews: Vec<Review> = github::get(
+        octocrab,
+        &route,
+        Some(&serde_json::json!({ "per_page": 100 })),
+    )
+    .await
+    .with_context(|| format!("Failed to read the reviews of PR #{}", number))?;
+
+    // Reviews come oldest first, so later verdicts replace earlier ones
+    let mut verdicts: BTreeMap<String, String> = BTreeMap::new();
+    for review in reviews {
+        let Some(user) = review.user else { continue };
+        if matches!(
+            review.state.as_str(),
+            "APPROVED" | "CHANGES_REQUESTED" | "DISMISSED"
+        ) {
+            verdicts.insert(user.login, review.state);
+        }
+    }
+
+    let mut state = ReviewState::default();
+    for (login, verdict) in verdicts {
+        match verdict.as_str() {
+            "APPROVED" => state.approved_by.push(login),
+            "CHANGES_REQUESTED" => state.changes_requested_by.push(login),
+            _ => {}
+        }
+    }
+    Ok(state)
+}
@@ -0,0 +1,309 @@
+use crate::github;
+use crate::output::say;
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use octocrab::models::workflows::Run;
+use octocrab::models::RunId;
+use octocrab::Octocrab;
+use serde::Deserialize;
+use std::time::{Duration, Instant};
+
+/// How far before our dispatch a run may have been created and still count as
+/// ours, to absorb clock skew between this machine and GitHub.
+const CLOCK_SKEW_ALLOWANCE_SECS: i64 = 30;
+
+const LOOKUP_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Statuses of runs that have not finished yet.
+const ACTIVE_STATUSES: &[&str] = &["queued", "in_progress", "waiting", "pending", "requested"];
+
+#[derive(Debug, Deserialize)]
+struct RunList<T = RunSummary> {
+    workflow_runs: Vec<T>,
+}
+
+/// The subset of a workflow run we need, including `display_title`, which the
+/// typed octocrab model lacks.
+#[derive(Debug, Deserialize)]
+pub struct RunSummary {
+    pub id: RunId,
+    pub display_title: String,
+    pub status: String,
+    pub conclusion: Option<String>,
+    pub head_branch: String,
+    pub head_sha: String,
+    pub created_at: DateTime<Utc>,
+    pub html_url: String,
+}
+
+impl RunSummary {
+    /// Whether the run has not finished yet.
+    pub fn is_active(&self) -> bool {
+        ACTIVE_STATUSES.contains(&self.status.as_str())
+    }
+
+    /// Whether the run was dispatched for `environment`.
+    ///
+    /// The API does not expose dispatch inputs, so this relies on the
+    /// workflow's `run-name` mentioning the target, matched as a whole word so
+    /// that `experimental1` does not match `experimental10`. Without it, the
+    /// title is the workflow name and no run matches, which the commands
+    /// relying on this warn about.
+    pub fn targets(&self, environment: &str) -> bool {
+        self.display_title
+            .split(|c: char| !(c.is_alphanumeric() || c == '-' || c == '_'))
+            .any(|word| word == environment)
+    }
+}
+
+pub enum WaitOutcome {
+    Completed(Run),
+    /// The deadline passed; carries the run if it was found at all.
+    TimedOut(Option<Run>),
+}
+
+/// Identifies the run a workflow dispatch should have created.
+pub struct Dispatch<'a> {
+    pub owner: &'a str,
+    pub repo: &'a str,
+    pub workflow_id: &'a str,
+    pub branch: &'a str,
+    /// The branch tip at dispatch time, which GitHub creates the run for.
+    pub head_sha: &'a str,
+    pub dispatched_at: DateTime<Utc>,
+    /// Runs already attributed to other dispatches of the same commit.
+    pub claimed: Vec<RunId>,
+}
+
+/// Finds the run created by `dispatch`, if GitHub has registered it yet.
+pub async fn find_dispatched_run(
+    octocrab: &Octocrab,
+    dispatch: &Dispatch<'_>,
+) -> Result<Option<Run>> {
+    let runs: RunList<Run> = github::get(
+        octocrab,
+        &format!(
+            "/repos/{}/{}/actions/workflows/{}/runs",
+            dispatch.owner, dispatch.repo, dispatch.workflow_id
+        ),
+        Some(&serde_json::json!({
+            "branch": dispatch.branch,
+            "event": "workflow_dispatch",
+            "per_page": 20,
+        })),
+    )
+    .await
+    .map_err(|err| {
+        github::context_unless_explained(err, "Failed to list workflow runs".to_string())
+    })?;
+
+    let earliest = dispatch.dispatched_at - chrono::Duration::seconds(CLOCK_SKEW_ALLOWANCE_SECS);
+    Ok(runs
+        .workflow_runs
+        .into_iter()
+        .filter(|run| {
+            run.head_sha == dispatch.head_sha
+                && run.created_at >= earliest
+                && !dispatch.claimed.contains(&run.id)
+        })
+        .min_by_key(|run| run.created_at))
+}
+
+/// Looks for the run created by `dispatch` for up to `window`, since GitHub
+/// takes a moment to register it.
+pub async fn locate_dispatched_run(
+    octocrab: &Octocrab,
+    dispatch: &Dispatch<'_>,
+    window: Duration,
+) -> Result<Option<Run>> {
+    let deadline = Instant::now() + window;
+    loop {
+        if let Some(run) = find_dispatched_run(octocrab, dispatch).await? {
+            return Ok(Some(run));
+        }
+        if Instant::now() + LOOKUP_INTERVAL > deadline {
+            return Ok(None);
+        }
+        tokio::time::sleep(LOOKUP_INTERVAL).await;
+    }
+}
+
+/// Lists unfinished runs of the workflow created within `window` that target
+/// `environment`.
+pub async fn active_runs_for_environment(
+    octocrab: &Octocrab,
+    owner: &str,
+    repo: &str,
+    workflow_id: &str,
+    environment: &str,
+    window: chrono::Duration,
+) -> Result<Vec<RunSummary>> {
+    let since = Utc::now() - window;
+    let params = serde_json::json!({
+        "created": format!(">={}", since.format("%Y-%m-%dT%H:%M:%SZ")),
+        "per_page": 100,
+    });
+    Ok(list_runs(octocrab, owner, repo, workflow_id, &params)
+        .await?
+        .into_iter()
+        .filter(|run| run.is_active() && run.targets(environment))
+        .collect())
+}
+
+/// Lists unfinished runs of the workflow for `head_sha` on `branch`, whichever
+/// environment they target.
+pub async fn active_runs_for_commit(
+    octocrab: &Octocrab,
+    owner: &str,
+    repo: &str,
+    workflow_id: &str,
+    branch: &str,
+    head_sha: &str,
+) -> Result<Vec<RunSummary>> {
+    let params = serde_json::json!({
+        "branch": branch,
+        "head_sha": head_sha,
+        "per_page": 100,
+    });
+    Ok(list_runs(octocrab, owner, repo, workflow_id, &params)
+        .await?
+        .into_iter()
+        // Older GitHub Enterprise Servers ignore the head_sha parameter
+        .filter(|run| run.is_active() && run.head_sha == head_sha)
+        .collect())
+}
+
+/// Lists the workflow's most recent runs, newest first.
+pub async fn recent_runs(
+    octocrab: &Octocrab,
+    owner: &str,
+    repo: &str,
+    workflow_id: &str,
+) -> Result<Vec<RunSummary>> {
+    let params = serde_json::json!({ "per_page": 100 });
+    list_runs(octocrab, owner, repo, workflow_id, &params).await
+}
+
+/// Fetches a single run of any workflow in the repository.
+pub async fn get_run(
+    octocrab: &Octocrab,
+    owner: &str,
+    repo: &str,
+    id: RunId,
+) -> Result<RunSummary> {
+    github::get(
+        octocrab,
+        &format!("/repos/{owner}/{repo}/actions/runs/{id}"),
+        None::<&()>,
+    )
+    .await
+    .map_err(|err| {
+        github::context_unless_explained(err, format!("Failed to fetch workflow run {}", id))
+    })
+}
+
+/// Asks GitHub to cancel the run, then follows it for up to `window` until it
+/// stops, returning its last known state.
+pub async fn cancel_run(
+    octocrab: &Octocrab,
+    owner: &str,
+    repo: &str,
+    id: RunId,
+    window: Duration,
+) -> Result<RunSummary> {
+    github::post::<serde_json::Value, _>(
+        octocrab,
+        &format!("/repos/{owner}/{repo}/actions/runs/{id}/cancel"),
+        &serde_json::json!({}),
+    )
+    .await
+    .map_err(|err| {
+        github::context_unless_explained(err, format!("Failed to cancel workflow run {}", id))
+    })?;
+
+    let deadline = Instant::now() + window;
+    loop {
+        let run = get_run(octocrab, owner, repo, id).await?;
+        if !run.is_active() || Instant::now() + LOOKUP_INTERVAL > deadline {
+            return Ok(run);
+        }
+        tokio::time::sleep(LOOKUP_INTERVAL).await;
+    }
+}
+
+async fn list_runs(
+    octocrab: &Octocrab,
+    owner: &str,
+    repo: &str,
+    workflow_id: &str,
+    params: &serde_json::Value,
+) -> Result<Vec<RunSummary>> {
+    let runs: RunList = github::get(
+        octocrab,
+        &format!("/repos/{owner}/{repo}/actions/workflows/{workflow_id}/runs"),
+        Some(params),
+    )
+    .await
+    .map_err(|err| {
+        github::context_unless_explained(err, "Failed to list recent workflow runs".to_string())
+    })?;
+    Ok(runs.workflow_runs)
+}
+
+/// Polls every `poll_interval` until the dispatched run reaches `completed` or
+/// `timeout` elapses, printing each status transition along the way. `run` is
+/// the run already located for `dispatch`, if any.
+pub async fn wait_for_run(
+    octocrab: &Octocrab,
+    dispatch: &Dispatch<'_>,
+    mut run: Option<Run>,
+    timeout: Duration,
+    poll_interval: Duration,
+) -> Result<WaitOutcome> {
+    let deadline = Instant::now() + timeout;
+    let mut last_status = String::new();
+
+    loop {
+        run = match run {
+            Some(run) => Some(
+                github::get(
+                    octocrab,
+                    &format!(
+                        "/repos/{}/{}/actions/runs/{}",
+                        dispatch.owner, dispatch.repo, run.id
+                    ),
+                    None::<&()>,
+                )
+                .await
+                .map_err(|err| {
+                    github::context_unless_explained(
+                        err,
+                        "Failed to fetch workflow run status".to_string(),
+                    )
+                })?,
+            ),
+            None => {
+                let found = find_dispatched_run(octocrab, dispatch).await?;
+                if let Some(run) = &found {
+                    say!("Found workflow run: {}", run.html_url);
+                }
+                found
+            }
+        };
+
+        if let Some(current) = &run {
+            if current.status != last_status {
+                say!("Run status: {}", current.status);
+                last_status = current.status.clone();
+            }
+        }
+        if let Some(completed) = run.take_if(|run| run.status == "completed") {
+            return Ok(WaitOutcome::Completed(completed));
+        }
+
+        if Instant::now() + poll_interval > deadline {
+            return Ok(WaitOutcome::TimedOut(run));
+        }
+        tokio::time::sleep(poll_interval).await;
+    }
+}
@@ -0,0 +1,488 @@
+use crate::output::print_table;
+use anyhow::{bail, Context, Result};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime};
+
+/// How long saving waits for another instance to finish saving.
+const LOCK_WAIT: Duration = Duration::from_secs(5);
+const LOCK_RETRY_INTERVAL: Duration = Duration::from_millis(100);
+/// A lock held this long was left behind by an instance that died saving.
+const STALE_LOCK_AGE: Duration = Duration::from_secs(30);
+
+static WAIT_FOR_LOCK: AtomicBool = AtomicBool::new(true);
+/// The lock file this process holds, for `release_lock` to remove on Ctrl-C.
+static HELD_LOCK: Mutex<Option<PathBuf>> = Mutex::new(None);
+
+/// Sets whether saving waits briefly for another instance holding the state
+/// file lock, or fails straight away.
+pub fn set_wait_for_lock(wait: bool) {
+    WAIT_FOR_LOCK.store(wait, Ordering::Relaxed);
+}
+
+/// A workflow dispatch made by this tool.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Deployment {
+    /// `owner/name` of the repository the workflow was dispatched in.
+    pub repository: String,
+    pub environment: String,
+    pub pr_number: Option<u64>,
+    pub branch: String,
+    /// The ref the workflow was dispatched from when it is not `branch`, e.g.
+    /// the base branch for a fork PR or a merge commit, or `--workflow-ref`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dispatch_ref: Option<String>,
+    pub commit_sha: String,
+    pub deployed_at: DateTime<Utc>,
+    /// `dispatched`, or the run's conclusion once it was waited for. It is
+    /// `dispatching` while the dispatch is sent, so that an interrupted deploy
+    /// leaves a trace. Missing from deployments recorded by older versions.
+    #[serde(default)]
+    pub result: Option<String>,
+    /// Seconds from dispatch until the run completed, when it was waited for.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub duration_secs: Option<u64>,
+}
+
+/// Everything the tool remembers between runs.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct State {
+    /// Every recorded deployment, oldest first.
+    #[serde(default)]
+    pub deployments: Vec<Deployment>,
+    /// The environments last picked in each repository, offered as the
+    /// default selection next time.
+    #[serde(default)]
+    pub last_selected: BTreeMap<String, Vec<String>>,
+    /// Repositories whose selection this run changed, so saving leaves the
+    /// others as another instance may have saved them.
+    #[serde(skip)]
+    selected_in: BTreeSet<String>,
+}
+
+/// The lock file held while saving, removed when dropped.
+struct StateLock(PathBuf);
+
+impl StateLock {
+    /// Creates the lock file next to `path`, waiting for another instance
+    /// holding it unless told not to.
+    fn acquire(path: &Path) -> Result<StateLock> {
+        let lock_path = path.with_extension("json.lock");
+        let wait = if WAIT_FOR_LOCK.load(Ordering::Relaxed) {
+            LOCK_WAIT
+        } else {
+            Duration::ZERO
+        };
+        let deadline = Instant::now() + wait;
+        loop {
+            let created = std::fs::OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(&lock_path);
+            match created {
+                Ok(mut file) => {
+                    let _ = writeln!(file, "{}", std::process::id());
+                    *HELD_LOCK.lock().unwrap_or_else(|err| err.into_inner()) =
+                        Some(lock_path.clone());
+                    return Ok(StateLock(lock_path));
+                }
+                Err(err) if err.kind() == ErrorKind::AlreadyExists => {
+                    if is_stale(&lock_path) {
+                        let _ = std::fs::remove_file(&lock_path);
+                        continue;
+                    }
+                    if Instant::now() >= deadline {
+                        bail!(
+                            "Another deploy is saving {}. Try again, or delete {} if none is running",
+                            path.display(),
+                            lock_path.display()
+                        );
+                    }
+                    std::thread::sleep(LOCK_RETRY_INTERVAL);
+                }
+                Err(err) => {
+                    return Err(err)
+                        .with_context(|| format!("Failed to create {}", lock_path.display()))
+                }
+            }
+        }
+    }
+}
+
+impl Drop for StateLock {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+        *HELD_LOCK.lock().unwrap_or_else(|err| err.into_inner()) = None;
+    }
+}
+
+/// Removes the state file lock if this process holds it, for an exit that
+/// skips destructors. The file is removed while `HELD_LOCK` is locked, so a
+/// concurrent call returns only once it is gone.
+pub fn release_lock() {
+    let mut held = HELD_LOCK.lock().unwrap_or_else(|err| err.into_inner());
+    if let Some(path) = held.take() {
+        let _ = std::fs::remove_file(path);
+    }
+}
+
+fn is_stale(lock_path: &Path) -> bool {
+    std::fs::metadata(lock_path)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+        .and_then(|modified| SystemTime::now().duration_since(modified).ok())
+        .is_some_and(|age| age > STALE_LOCK_AGE)
+}
+
+/// Whether `a` and `b` record the same dispatch.
+fn same_dispatch(a: &Deployment, b: &Deployment) -> bool {
+    a.repository == b.repository && a.environment == b.environment && a.deployed_at == b.deployed_at
+}
+
+impl State {
+    fn path() -> Option<PathBuf> {
+        Some(dirs::config_dir()?.join("deploy").join("state.json"))
+    }
+
+    /// Loads the state file, treating a missing or unreadable one as empty.
+    pub fn load() -> State {
+        let Some(path) = Self::path() else {
+            return State::default();
+        };
+        let Ok(contents) = std::fs::read_to_string(&path) else {
+            return State::default();
+        };
+        serde_json::from_str(&contents).unwrap_or_else(|err| {
+            eprintln!(
+                "Warning: ignoring unreadable state file {}: {}",
+                path.display(),
+                err
+            );
+            State::default()
+        })
+    }
+
+    /// Saves this run's changes on top of the file as it is now, so that
+    /// deploys recorded by another instance since loading are kept. The file is
+    /// replaced whole, so a reader never sees it half written.
+    pub fn save(&self) -> Result<()> {
+        let path = Self::path().context("Could not determine the config directory")?;
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)
+                .with_context(|| format!("Failed to create {}", dir.display()))?;
+        }
+        let _lock = StateLock::acquire(&path)?;
+
+        let mut merged: State = std::fs::read_to_string(&path)
+            .ok()
+            .and_then(|contents| serde_json::from_str(&contents).ok())
+            .unwrap_or_default();
+        for deployment in &self.deployments {
+            match merged
+                .deployments
+                .iter_mut()
+                .find(|saved| same_dispatch(saved, deployment))
+            {
+                Some(saved) => *saved = deployment.clone(),
+                None => merged.deployments.push(deployment.clone()),
+            }
+        }
+        merged.deployments.sort_by_key(|d| d.deployed_at);
+        for repository in &self.selected_in {
+            if let Some(selection) = self.last_selected.get(repository) {
+                merged
+                    .last_selected
+                    .insert(repository.clone(), selection.clone());
+            }
+        }
+
+        let temp_path = path.with_extension("json.tmp");
+        std::fs::write(&temp_path, serde_json::to_string_pretty(&merged)?)
+            .with_context(|| format!("Failed to write {}", temp_path.display()))?;
+        std::fs::rename(&temp_path, &path)
+            .with_context(|| format!("Failed to write {}", path.display()))
+    }
+
+    pub fn record(&mut self, deployment: Deployment) {
+        self.deployments.push(deployment);
+    }
+
+    pub fn remember_selection(&mut self, repository: &str, environments: &[String]) {
+        self.last_selected
+            .insert(repository.to_string(), environments.to_vec());
+        self.selected_in.insert(repository.to_string());
+    }
+
+    /// Records how the run of the deployment dispatched at `deployed_at` ended.
+    pub fn set_result(
+        &mut self,
+        repository: &str,
+        environment: &str,
+        deployed_at: DateTime<Utc>,
+        result: &str,
+    ) {
+        if let Some(deployment) = self.find_mut(repository, environment, deployed_at) {
+            deployment.result = Some(result.to_string());
+        }
+    }
+
+    /// Records how long the run of the deployment dispatched at `deployed_at`
+    /// took to complete.
+    pub fn set_duration(
+        &mut self,
+        repository: &str,
+        environment: &str,
+        deployed_at: DateTime<Utc>,
+        duration_secs: u64,
+    ) {
+        if let Some(deployment) = self.find_mut(repository, environment, deployed_at) {
+            deployment.duration_secs = Some(duration_secs);
+        }
+    }
+
+    fn find_mut(
+        &mut self,
+        repository: &str,
+        environment: &str,
+        deployed_at: DateTime<Utc>,
+    ) -> Option<&mut Deployment> {
+        self.deployments.iter_mut().rev().find(|d| {
+            d.repository == repository
+                && d.environment == environment
+                && d.deployed_at == deployed_at
+        })
+    }
+
+    /// Deployments in `repository`, oldest first, optionally only those to
+    /// `environment` and only the most recent `limit`.
+    pub fn history(
+        &self,
+        repository: &str,
+        environment: Option<&str>,
+        limit: Option<usize>,
+    ) -> Vec<&Deployment> {
+        let matching: Vec<&Deployment> = self
+            .deployments
+            .iter()
+            .filter(|d| d.repository == repository)
+            .filter(|d| environment.is_none_or(|env| d.environment == env))
+            .collect();
+        let skip = limit.map_or(0, |limit| matching.len().saturating_sub(limit));
+        matching[skip..].to_vec()
+    }
+
+    /// The environments last picked in `repository`, if any.
+    pub fn last_selection(&self, repository: &str) -> &[String] {
+        self.last_selected
+            .get(repository)
+            .map_or(&[], Vec::as_slice)
+    }
+
+    /// The PR most recently deployed from `repository`.
+    pub fn last_deployed_pr(&self, repository: &str) -> Option<u64> {
+        self.deployments
+            .iter()
+            .rev()
+            .filter(|d| d.repository == repository && d.went_out())
+            .find_map(|d| d.pr_number)
+    }
+
+    /// The most recent deployment to `environment` in `repository`.
+    pub fn last_deployment(&self, repository: &str, environment: &str) -> Option<&Deployment> {
+        self.deployments
+            .iter()
+            .rev()
+            .find(|d| d.repository == repository && d.environment == environment && d.went_out())
+    }
+
+    /// The most recent successful deployment to `environment` of a different
+    /// commit than the latest one, i.e. what a rollback returns to.
+    pub fn previous_deployment(&self, repository: &str, environment: &str) -> Option<&Deployment> {
+        let current = self.last_deployment(repository, environment)?;
+        self.deployments.iter().rev().find(|d| {
+            d.repository == repository
+                && d.environment == environment
+                && d.succeeded()
+                && d.commit_sha != current.commit_sha
+        })
+    }
+}
+
+/// Renders how long ago `time` was, e.g. `5m ago` or `2h ago`.
+pub fn format_age(time: DateTime<Utc>) -> String {
+    let age = Utc::now().signed_duration_since(time);
+    if age.num_minutes() < 1 {
+        "just now".to_string()
+    } else if age.num_hours() < 1 {
+        format!("{}m ago", age.num_minutes())
+    } else if age.num_days() < 1 {
+        format!("{}h ago", age.num_hours())
+    } else {
+        format!("{}d ago", age.num_days())
+    }
+}
+
+/// Renders a duration in seconds, e.g. `42s`, `3m42s` or `1h05m00s`.
+pub fn format_duration(secs: u64) -> String {
+    let (hours, minutes, seconds) = (secs / 3600, secs / 60 % 60, secs % 60);
+    if hours > 0 {
+        format!("{}h{:02}m{:02}s", hours, minutes, seconds)
+    } else if minutes > 0 {
+        format!("{}m{:02}s", minutes, seconds)
+    } else {
+        format!("{}s", seconds)
+    }
+}
+
+impl Deployment {
+    /// Whether the dispatch was not refused, so the environment may run it.
+    pub fn went_out(&self) -> bool {
+        self.result.as_deref() != Some("failed")
+    }
+
+    /// Whether the deploy is known not to have gone wrong: it was dispatched
+    /// and not waited for, or its run succeeded. Older versions recorded
+    /// only dispatched deploys, without a result.
+    pub fn succeeded(&self) -> bool {
+        matches!(
+            self.result.as_deref(),
+            None | Some("dispatched" | "success")
+        )
+    }
+
+    /// The ref the workflow was dispatched from, for dispatching it again.
+    pub fn dispatch_ref(&self) -> &str {
+        self.dispatch_ref.as_deref().unwrap_or(&self.branch)
+    }
+
+    /// Short description used to annotate environment choices.
+    pub fn summary(&self) -> String {
+        let what = match self.pr_number {
+            Some(number) => format!("PR #{}", number),
+            None => self.branch.clone(),
+        };
+        format!("{}, {}", what, format_age(self.deployed_at))
+    }
+}
+
+/// Prints the last deployment of each environment as a table.
+pub fn print_status(state: &State, repository: &str, environments: &[String]) {
+    let rows: Vec<[String; 5]> = environments
+        .iter()
+        .map(|env| match state.last_deployment(repository, env) {
+            Some(d) => [
+                env.clone(),
+                d.pr_number.map(|n| format!("#{n}")).unwrap_or_default(),
+                d.branch.clone(),
+                crate::short_sha(&d.commit_sha).to_string(),
+                format_age(d.deployed_at),
+            ],
+            None => [
+                env.clone(),
+                String::new(),
+                String::new(),
+                String::new(),
+                "-".into(),
+            ],
+        })
+        .collect();
+
+    print_table(
+        &["ENVIRONMENT", "PR", "BRANCH", "COMMIT", "DEPLOYED"],
+        &rows,
+    );
+}
+
+/// Prints `deployments` as a table, in the order given.
+pub fn print_history(deployments: &[&Deployment]) {
+    let rows: Vec<[String; 7]> = deployments
+        .iter()
+        .map(|d| {
+            [
+                d.deployed_at
+                    .with_timezone(&chrono::Local)
+                    .format("%Y-%m-%d %H:%M")
+                    .to_string(),
+                d.environment.clone(),
+                d.pr_number.map(|n| format!("#{n}")).unwrap_or_default(),
+                d.branch.clone(),
+                crate::short_sha(&d.commit_sha).to_string(),
+                d.result.clone().unwrap_or_else(|| "-".into()),
+                d.duration_secs
+                    .map(format_duration)
+                    .unwrap_or_else(|| "-".into()),
+            ]
+        })
+        .collect();
+
+    print_table(
+        &[
+            "TIME",
+            "ENVIRONMENT",
+            "PR",
+            "BRANCH",
+            "COMMIT",
+            "RESULT",
+            "DURATION",
+        ],
+        &rows,
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn deployment(commit_sha: &str, result: &str, minutes_ago: i64) -> Deployment {
+        Deployment {
+            repository: "org/app".to_string(),
+            environment: "experimental1".to_string(),
+            pr_number: None,
+            branch: "main".to_string(),
+            dispatch_ref: None,
+            commit_sha: commit_sha.to_string(),
+            deployed_at: Utc::now() - chrono::Duration::minutes(minutes_ago),
+            result: Some(result.to_string()),
+            duration_secs: None,
+        }
+    }
+
+    #[test]
+    fn rollback_skips_deploys_that_did_not_succeed() {
+        let state = State {
+            deployments: vec![
+                deployment("aaaaaaa", "success", 50),
+                deployment("bbbbbbb", "failure", 40),
+                deployment("ddddddd", "dispatching", 30),
+                deployment("eeeeeee", "unhealthy", 20),
+                deployment("ccccccc", "dispatched", 10),
+            ],
+            ..State::default()
+        };
+        let previous = state
+            .previous_deployment("org/app", "experimental1")
+            .unwrap();
+        assert_eq!(previous.commit_sha, "aaaaaaa");
+    }
+
+    #[test]
+    fn dispatch_ref_falls_back_to_the_branch() {
+        let recorded = r#"{
+            "repository": "org/app",
+            "environment": "experimental1",
+            "pr_number": 12,
+            "branch": "fork-feature",
+            "commit_sha": "0123456789abcdef0123456789abcdef01234567",
+            "deployed_at": "2026-01-02T03:04:05Z"
+        }"#;
+        let mut deployment: Deployment = serde_json::from_str(recorded).unwrap();
+        assert_eq!(deployment.dispatch_ref(), "fork-feature");
+        deployment.dispatch_ref = Some("main".to_string());
+        assert_eq!(deployment.dispatch_ref(), "main");
+    }
+}
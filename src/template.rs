@@ -0,0 +1,54 @@
+use anyhow::{bail, Context, Result};
+
+/// Checks that `template` only uses `placeholders`, written as `{name}`, and
+/// closes each. `what` names the template in errors, e.g. `target template`.
+pub fn check(template: &str, placeholders: &[&str], what: &str) -> Result<()> {
+    let mut rest = template;
+    while let Some(start) = rest.find(['{', '}']) {
+        if rest[start..].starts_with('}') {
+            bail!("Unmatched '}}' in {} '{}'", what, template);
+        }
+        let end = rest[start..]
+            .find('}')
+            .with_context(|| format!("Unclosed '{{' in {} '{}'", what, template))?;
+        let name = &rest[start + 1..start + end];
+        if !placeholders.contains(&name) {
+            bail!(
+                "Unknown placeholder {{{}}} in {} '{}'; use {}",
+                name,
+                what,
+                template,
+                placeholders
+                    .iter()
+                    .map(|name| format!("{{{}}}", name))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            );
+        }
+        rest = &rest[start + end + 1..];
+    }
+    Ok(())
+}
+
+/// Whether `template` uses the placeholder `name`.
+pub fn uses(template: &str, name: &str) -> bool {
+    template.contains(&format!("{{{}}}", name))
+}
+
+/// Replaces each placeholder of a template that passed `check` with
+/// `value(name)`. Values are inserted as is, so one that looks like a
+/// placeholder is left alone.
+pub fn render(template: &str, mut value: impl FnMut(&str) -> String) -> String {
+    let mut rendered = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        let Some(end) = rest[start..].find('}') else {
+            break;
+        };
+        rendered.push_str(&rest[..start]);
+        rendered.push_str(&value(&rest[start + 1..start + end]));
+        rest = &rest[start + end + 1..];
+    }
+    rendered.push_str(rest);
+    rendered
+}
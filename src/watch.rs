@@ -0,0 +1,89 @@
+use crate::output::print_table;
+use crate::runs::{self, RunSummary};
+use crate::{github, state};
+use anyhow::Result;
+use dialoguer::console::{Key, Term};
+use octocrab::Octocrab;
+use std::time::Duration;
+
+const REFRESH_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Redraws the latest run of the workflow for each environment every few
+/// seconds, until `q` is pressed.
+pub async fn run(
+    octocrab: &Octocrab,
+    owner: &str,
+    repo: &str,
+    workflow_id: &str,
+    environments: &[String],
+) -> Result<()> {
+    let term = Term::stdout();
+    let (quit, mut quit_requested) = tokio::sync::mpsc::unbounded_channel();
+    if term.is_term() {
+        // `read_key` blocks, so keys are read on a thread of their own
+        std::thread::spawn(move || loop {
+            match Term::stdout().read_key() {
+                Ok(Key::Char('q' | 'Q') | Key::Escape | Key::CtrlC) => {
+                    let _ = quit.send(());
+                    break;
+                }
+                Ok(_) => {}
+                Err(_) => break,
+            }
+        });
+    }
+
+    loop {
+        let runs = runs::recent_runs(octocrab, owner, repo, workflow_id).await;
+        if term.is_term() {
+            term.clear_screen()?;
+        }
+        println!(
+            "Workflow {} in {}/{}, refreshed every {}s. Press q to quit.\n",
+            workflow_id,
+            owner,
+            repo,
+            REFRESH_INTERVAL.as_secs()
+        );
+        match runs {
+            Ok(runs) => print_runs(&runs, environments),
+            Err(err) => println!("Failed to refresh: {}", github::one_line(&err)),
+        }
+
+        tokio::select! {
+            _ = tokio::time::sleep(REFRESH_INTERVAL) => {}
+            Some(()) = quit_requested.recv() => return Ok(()),
+        }
+    }
+}
+
+/// Prints the newest of `runs` that targets each environment.
+fn print_runs(runs: &[RunSummary], environments: &[String]) {
+    let rows: Vec<[String; 5]> = environments
+        .iter()
+        .map(|env| match runs.iter().find(|run| run.targets(env)) {
+            Some(run) => [
+                env.clone(),
+                match (run.status.as_str(), &run.conclusion) {
+                    ("completed", Some(conclusion)) => conclusion.clone(),
+                    (status, _) => status.to_string(),
+                },
+                run.head_branch.clone(),
+                state::format_age(run.created_at),
+                run.html_url.clone(),
+            ],
+            None => [
+                env.clone(),
+                "-".into(),
+                String::new(),
+                String::new(),
+                String::new(),
+            ],
+        })
+        .collect();
+
+    print_table(
+        &["ENVIRONMENT", "STATUS", "BRANCH", "STARTED", "RUN"],
+        &rows,
+    );
+}
@@ -0,0 +1,396 @@
+use crate::exit::{self, Failure};
+use crate::github;
+use crate::template;
+use anyhow::{Context, Result};
+use octocrab::models::repos::Content;
+use octocrab::models::workflows::WorkFlow;
+use octocrab::Octocrab;
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap};
+
+#[derive(Debug, Deserialize)]
+struct WorkflowList {
+    workflows: Vec<WorkFlow>,
+}
+
+/// The parts of a workflow file we care about.
+#[derive(Debug, Default, Deserialize)]
+pub struct WorkflowFile {
+    #[serde(rename = "on", default)]
+    triggers: Option<Triggers>,
+    /// The title of each run, e.g. `Deploy to ${{ inputs.environment }}`.
+    #[serde(rename = "run-name")]
+    run_name: Option<String>,
+}
+
+/// The `on:` key, which may be a single event, a list, or a map of events to
+/// their configuration.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum Triggers {
+    Event(String),
+    Events(Vec<String>),
+    Configured(HashMap<String, serde_yaml::Value>),
+}
+
+/// The configuration of a `workflow_dispatch` trigger.
+#[derive(Debug, Default, Deserialize)]
+struct DispatchTrigger {
+    #[serde(default)]
+    inputs: BTreeMap<String, InputSpec>,
+}
+
+/// A declared `workflow_dispatch` input.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct InputSpec {
+    #[serde(default)]
+    pub required: bool,
+    pub default: Option<serde_yaml::Value>,
+    /// `string`, `choice`, `boolean`, `number` or `environment`.
+    #[serde(rename = "type")]
+    pub kind: Option<String>,
+    /// The allowed values of a `choice` input.
+    #[serde(default)]
+    pub options: Vec<serde_yaml::Value>,
+}
+
+impl InputSpec {
+    /// The values a `choice` input allows, or `None` for any other input.
+    pub fn choices(&self) -> Option<Vec<String>> {
+        if self.kind.as_deref() != Some("choice") {
+            return None;
+        }
+        Some(
+            self.options
+                .iter()
+                .filter_map(|option| match option {
+                    serde_yaml::Value::String(option) => Some(option.clone()),
+                    serde_yaml::Value::Number(option) => Some(option.to_string()),
+                    serde_yaml::Value::Bool(option) => Some(option.to_string()),
+                    _ => None,
+                })
+                .collect(),
+        )
+    }
+}
+
+impl WorkflowFile {
+    pub fn parse(contents: &str) -> Result<WorkflowFile> {
+        serde_yaml::from_str(contents).context("Failed to parse workflow file")
+    }
+
+    /// Whether the workflow can be triggered through the dispatch API.
+    pub fn is_dispatchable(&self) -> bool {
+        match &self.triggers {
+            Some(Triggers::Event(event)) => event == "workflow_dispatch",
+            Some(Triggers::Events(events)) => events.iter().any(|e| e == "workflow_dispatch"),
+            Some(Triggers::Configured(events)) => events.contains_key("workflow_dispatch"),
+            None => false,
+        }
+    }
+
+    /// Whether the `run-name` shows the dispatch input `input`, which is the
+    /// only way to tell from a run which target it deploys.
+    pub fn run_name_shows(&self, input: &str) -> bool {
+        let Some(run_name) = &self.run_name else {
+            return false;
+        };
+        let dotted = format!("inputs.{}", input);
+        let is_name_char = |c: char| c.is_alphanumeric() || c == '_' || c == '-';
+        let dotted_found = run_name.match_indices(&dotted).any(|(at, _)| {
+            run_name[at + dotted.len()..]
+                .chars()
+                .next()
+                .is_none_or(|c| !is_name_char(c))
+        });
+        dotted_found
+            || run_name.contains(&format!("inputs['{}']", input))
+            || run_name.contains(&format!("inputs[\"{}\"]", input))
+    }
+
+    /// The inputs declared by the `workflow_dispatch` trigger, or `None` when
+    /// the workflow cannot be dispatched.
+    pub fn dispatch_inputs(&self) -> Option<BTreeMap<String, InputSpec>> {
+        if !self.is_dispatchable() {
+            return None;
+        }
+        let Some(Triggers::Configured(events)) = &self.triggers else {
+            return Some(BTreeMap::new());
+        };
+        let trigger = match events.get("workflow_dispatch") {
+            Some(serde_yaml::Value::Null) | None => DispatchTrigger::default(),
+            Some(value) => serde_yaml::from_value(value.clone()).unwrap_or_default(),
+        };
+        Some(trigger.inputs)
+    }
+}
+
+/// Placeholders a target template may use.
+const TARGET_PLACEHOLDERS: &[&str] = &["env", "pr", "sha", "branch"];
+
+/// The value of the environment input, with `{env}`, `{pr}`, `{sha}` and
+/// `{branch}` replaced, e.g. `{env}/pr-{pr}`.
+#[derive(Debug, Clone)]
+pub struct TargetTemplate(String);
+
+impl Default for TargetTemplate {
+    fn default() -> TargetTemplate {
+        TargetTemplate("{env}".to_string())
+    }
+}
+
+impl TargetTemplate {
+    /// Checks that `template` only uses known placeholders and closes each.
+    pub fn parse(template: &str) -> Result<TargetTemplate> {
+        template::check(template, TARGET_PLACEHOLDERS, "target template")?;
+        Ok(TargetTemplate(template.to_string()))
+    }
+
+    /// Whether the template needs a PR number.
+    pub fn uses_pr(&self) -> bool {
+        template::uses(&self.0, "pr")
+    }
+
+    fn render(&self, deploy: &DeployTarget) -> String {
+        template::render(&self.0, |name| match name {
+            "env" => deploy.environment.to_string(),
+            "pr" => deploy.pr.map(|pr| pr.to_string()).unwrap_or_default(),
+            "sha" => deploy.commit.to_string(),
+            _ => deploy.branch.to_string(),
+        })
+    }
+}
+
+/// What a dispatch deploys, for its inputs.
+pub struct DeployTarget<'a> {
+    pub environment: &'a str,
+    /// The commit as sent in the commit input.
+    pub commit: &'a str,
+    pub pr: Option<u64>,
+    pub branch: &'a str,
+}
+
+/// Names of the dispatch inputs that carry the commit and the environment,
+/// and how the environment's value is written.
+#[derive(Debug, Clone)]
+pub struct InputKeys {
+    pub sha: String,
+    pub target: String,
+    pub target_template: TargetTemplate,
+}
+
+impl Default for InputKeys {
+    fn default() -> InputKeys {
+        InputKeys {
+            sha: "commit_sha".to_string(),
+            target: "target".to_string(),
+            target_template: TargetTemplate::default(),
+        }
+    }
+}
+
+impl InputKeys {
+    /// The dispatch inputs deploying `deploy`.
+    pub fn inputs(&self, deploy: &DeployTarget) -> serde_json::Map<String, serde_json::Value> {
+        let mut inputs = serde_json::Map::new();
+        inputs.insert(self.sha.clone(), deploy.commit.into());
+        inputs.insert(
+            self.target.clone(),
+            self.target_template.render(deploy).into(),
+        );
+        inputs
+    }
+}
+
+/// Checks the `inputs` about to be dispatched against those the workflow
+/// file at `path` declares.
+pub fn validate_inputs(
+    path: &str,
+    declared: &BTreeMap<String, InputSpec>,
+    inputs: &serde_json::Map<String, serde_json::Value>,
+) -> Result<()> {
+    let unknown: Vec<&str> = inputs
+        .keys()
+        .filter(|name| !declared.contains_key(*name))
+        .map(String::as_str)
+        .collect();
+    let missing: Vec<&str> = declared
+        .iter()
+        .filter(|(name, spec)| {
+            spec.required && spec.default.is_none() && !inputs.contains_key(*name)
+        })
+        .map(|(name, _)| name.as_str())
+        .collect();
+    let not_allowed: Vec<String> = declared
+        .iter()
+        .filter_map(|(name, spec)| {
+            let choices = spec.choices()?;
+            let sent = match inputs.get(name)? {
+                serde_json::Value::String(sent) => sent.clone(),
+                sent => sent.to_string(),
+            };
+            (!choices.contains(&sent)).then(|| {
+                format!(
+                    "'{}' is not one of the options of input {} ({})",
+                    sent,
+                    name,
+                    choices.join(", ")
+                )
+            })
+        })
+        .collect();
+
+    let mut problems = Vec::new();
+    if !unknown.is_empty() {
+        let accepted: Vec<&str> = declared.keys().map(String::as_str).collect();
+        problems.push(format!(
+            "it does not declare input(s) {} (declared: {})",
+            unknown.join(", "),
+            if accepted.is_empty() {
+                "none".to_string()
+            } else {
+                accepted.join(", ")
+            }
+        ));
+    }
+    if !missing.is_empty() {
+        problems.push(format!(
+            "it requires input(s) {} that are not being sent",
+            missing.join(", ")
+        ));
+    }
+    problems.extend(not_allowed);
+    if !problems.is_empty() {
+        return Err(exit::fail(
+            Failure::Rejected,
+            format!(
+                "Workflow inputs do not match {}: {}",
+                path,
+                problems.join("; ")
+            ),
+        ));
+    }
+    Ok(())
+}
+
+/// Downloads and parses the workflow file at `path`, e.g.
+/// `.github/workflows/deploy.yml`, as of `git_ref` or the default branch.
+pub async fn fetch_workflow_file(
+    octocrab: &Octocrab,
+    owner: &str,
+    repo: &str,
+    path: &str,
+    git_ref: Option<&str>,
+) -> Result<WorkflowFile> {
+    let route = format!(
+        "/repos/{}/{}/contents/{}",
+        owner,
+        repo,
+        github::encode_ref(path)
+    );
+    let content: Content = github::get(
+        octocrab,
+        &route,
+        git_ref
+            .map(|git_ref| serde_json::json!({ "ref": git_ref }))
+            .as_ref(),
+    )
+    .await
+    .map_err(|err| {
+        github::context_unless_explained(err, format!("Failed to fetch workflow file {}", path))
+    })?;
+    let contents = content
+        .decoded_content()
+        .with_context(|| format!("Workflow file {} is empty", path))?;
+    WorkflowFile::parse(&contents).with_context(|| format!("Invalid workflow file {}", path))
+}
+
+/// Lists the repository's active workflows that have a `workflow_dispatch`
+/// trigger.
+pub async fn dispatchable_workflows(
+    octocrab: &Octocrab,
+    owner: &str,
+    repo: &str,
+) -> Result<Vec<WorkFlow>> {
+    let workflows = github::get::<WorkflowList, _>(
+        octocrab,
+        &format!("/repos/{}/{}/actions/workflows", owner, repo),
+        Some(&serde_json::json!({ "per_page": 100 })),
+    )
+    .await
+    .map_err(|err| github::context_unless_explained(err, "Failed to list workflows".to_string()))?
+    .workflows;
+
+    let active: Vec<WorkFlow> = workflows
+        .into_iter()
+        .filter(|workflow| workflow.state == "active")
+        .collect();
+    let files = futures::future::join_all(
+        active
+            .iter()
+            .map(|workflow| fetch_workflow_file(octocrab, owner, repo, &workflow.path, None)),
+    )
+    .await;
+
+    Ok(active
+        .into_iter()
+        .zip(files)
+        .filter(|(_, file)| file.as_ref().is_ok_and(WorkflowFile::is_dispatchable))
+        .map(|(workflow, _)| workflow)
+        .collect())
+}
+
+/// Fetches a workflow by numeric id or file name.
+pub async fn get_workflow(
+    octocrab: &Octocrab,
+    owner: &str,
+    repo: &str,
+    workflow_id: &str,
+) -> Result<WorkFlow> {
+    github::get(
+        octocrab,
+        &format!("/repos/{owner}/{repo}/actions/workflows/{workflow_id}"),
+        None::<&()>,
+    )
+    .await
+    .with_context(|| format!("Failed to fetch workflow {}", workflow_id))
+}
+
+/// The web page listing runs of the workflow, e.g.
+/// `https://github.com/org/repo/actions/workflows/deploy.yml`.
+pub async fn workflow_runs_page(
+    octocrab: &Octocrab,
+    owner: &str,
+    repo: &str,
+    workflow_id: &str,
+) -> Result<String> {
+    let workflow = get_workflow(octocrab, owner, repo, workflow_id).await?;
+
+    // html_url points at the workflow file: <repo url>/blob/<branch>/<path>
+    let html_url = workflow.html_url.as_str();
+    let repo_url = html_url.split("/blob/").next().unwrap_or(html_url);
+    let file_name = workflow.path.rsplit('/').next().unwrap_or(&workflow.path);
+    Ok(format!("{}/actions/workflows/{}", repo_url, file_name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn with_run_name(run_name: &str) -> WorkflowFile {
+        WorkflowFile::parse(&format!("on: workflow_dispatch\nrun-name: {}\n", run_name)).unwrap()
+    }
+
+    #[test]
+    fn run_name_shows_the_target_input() {
+        let input = "environment";
+        assert!(with_run_name("Deploy to ${{ inputs.environment }}").run_name_shows(input));
+        assert!(with_run_name("${{ github.event.inputs.environment }}").run_name_shows(input));
+        assert!(with_run_name("\"${{ inputs['environment'] }}\"").run_name_shows(input));
+        assert!(!with_run_name("Deploy ${{ inputs.environment_name }}").run_name_shows(input));
+        assert!(!with_run_name("Deploy ${{ inputs.sha }}").run_name_shows(input));
+        assert!(!WorkflowFile::parse("on: workflow_dispatch\n")
+            .unwrap()
+            .run_name_shows(input));
+    }
+}